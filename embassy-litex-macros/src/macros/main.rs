@@ -93,16 +93,10 @@ pub fn run_entry(args: TokenStream, item: TokenStream) -> TokenStream {
             quote! {
 
                     
-                    EXECUTOR_LIST[15].start().must_spawn(__embassy_main());
-                    interrupt::initialize();
-
-                    loop {
-                        unsafe {
-                            core::arch::asm!("wfi");
-                        }
-                    }
-                
-                    loop {}
+                    ::embassy_litex::executor::THREAD_EXECUTOR.run(|spawner| {
+                        spawner.must_spawn(__embassy_main());
+                        interrupt::initialize();
+                    })
             
             },
     );
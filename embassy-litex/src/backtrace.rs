@@ -0,0 +1,130 @@
+//! Frame-pointer backtrace.
+//!
+//! With frame pointers (`-C force-frame-pointers=yes`, set in the example's
+//! `.cargo/config`) every frame stores the return address at `fp - 4` and the
+//! caller's frame pointer at `fp - 8`. [`walk`] follows that chain as long as
+//! the frame pointers stay inside the stack from `link.x` or the exception
+//! stack, grow towards the stack top and [`MAX_DEPTH`] is not reached.
+//!
+//! Each frame is printed as one `#n pc=0x…` line with the return address, which
+//! the `symbolize` host tool translates to functions and source lines. An
+//! optional on-target symbol table set with [`set_symbols`] adds the function
+//! names directly.
+
+use core::arch::asm;
+use core::cell::Cell;
+
+use critical_section::Mutex;
+
+use crate::exception;
+use crate::serial::print_fmt_func;
+
+/// Maximum number of frames printed.
+pub const MAX_DEPTH: usize = 32;
+
+/// Start address and name of a function, for [`set_symbols`].
+pub struct Symbol {
+    pub addr: usize,
+    pub name: &'static str,
+}
+
+static SYMBOLS: Mutex<Cell<&'static [Symbol]>> = Mutex::new(Cell::new(&[]));
+
+extern "C" {
+    static _stack_start: u8;
+    static __sstack: u8;
+}
+
+
+/// Lower and upper end of the stack from `link.x`.
+pub fn stack_bounds() -> (usize, usize) {
+    let a = core::ptr::addr_of!(_stack_start) as usize;
+    let b = core::ptr::addr_of!(__sstack) as usize;
+    return (a.min(b), a.max(b));
+}
+
+/// Set the symbol table used to name the frames, sorted by address.
+pub fn set_symbols(symbols: &'static [Symbol]) {
+    critical_section::with(|cs| SYMBOLS.borrow(cs).set(symbols));
+}
+
+/// Function containing `pc` and the offset into it, if a symbol table is set.
+pub fn lookup(pc: usize) -> Option<(&'static str, usize)> {
+    let symbols = critical_section::with(|cs| SYMBOLS.borrow(cs).get());
+    let index = match symbols.binary_search_by(|s| s.addr.cmp(&pc)) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let symbol = &symbols[index];
+    return Some((symbol.name, pc - symbol.addr));
+}
+
+/// Stack containing `fp`, as an index into the known stacks.
+fn stack_of(fp: usize) -> Option<usize> {
+    let stacks = [stack_bounds(), exception::stack_bounds()];
+    // the frame record lies below fp, fp itself may be the top of the stack
+    stacks.iter().position(|&(low, high)| fp >= low + 8 && fp <= high)
+}
+
+/// Call `f` with the depth and return address of every frame starting at
+/// frame pointer `fp`, returns the number of frames.
+pub fn walk(mut fp: usize, mut f: impl FnMut(usize, usize)) -> usize {
+    let mut previous: Option<(usize, usize)> = None;
+    let mut depth = 0;
+
+    while depth < MAX_DEPTH {
+        if fp % 4 != 0 {
+            break;
+        }
+        let Some(stack) = stack_of(fp) else { break };
+        // frames of callers are higher up on the same stack, the exception
+        // handler's caller is on the interrupted stack
+        if let Some((previous_fp, previous_stack)) = previous {
+            if stack == previous_stack && fp <= previous_fp {
+                break;
+            }
+        }
+
+        let ra = unsafe { ((fp - 4) as *const usize).read_volatile() };
+        if ra == 0 {
+            break;
+        }
+        f(depth, ra);
+        depth += 1;
+
+        previous = Some((fp, stack));
+        fp = unsafe { ((fp - 8) as *const usize).read_volatile() };
+    }
+    return depth;
+}
+
+/// Print the frames starting at `fp`.
+pub fn print_from(fp: usize) {
+    print_fmt_func(format_args!("Backtrace:\r\n"));
+    let depth = walk(fp, |n, pc| {
+        match lookup(pc) {
+            Some((name, offset)) => print_fmt_func(format_args!("#{} pc={:#010x} {}+{:#x}\r\n", n, pc, name, offset)),
+            None => print_fmt_func(format_args!("#{} pc={:#010x}\r\n", n, pc)),
+        }
+    });
+    if depth == MAX_DEPTH {
+        print_fmt_func(format_args!("... stopped after {} frames\r\n", MAX_DEPTH));
+    }
+}
+
+/// Frame pointer of the calling function.
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe {
+        asm!("mv {0}, s0", out(reg) fp);
+    }
+    return fp;
+}
+
+/// Print the frames of the caller.
+#[inline(always)]
+pub fn print() {
+    print_from(frame_pointer());
+}
@@ -0,0 +1,78 @@
+//! Priority-ceiling locking.
+//!
+//! `critical_section::with` disables all interrupts. [`CeilingRawMutex`]
+//! instead raises the active software interrupt level to a ceiling priority
+//! for the duration of the lock: executor levels and software interrupts up to
+//! the ceiling are held back, while higher levels and external interrupts keep
+//! running.
+//!
+//! The ceiling has to be at least the highest level that locks the mutex,
+//! locking from a higher level panics. Locks with a lower ceiling may be
+//! nested inside, only the level of the running executor is checked.
+//!
+//! External interrupt, timer and exception handlers are not held back by a
+//! ceiling, locking from them panics. Data shared with them needs a
+//! `CriticalSectionRawMutex`.
+//!
+//! ```ignore
+//! use embassy_litex::ceiling::CeilingRawMutex;
+//! use embassy_sync::channel::Channel;
+//!
+//! // shared by tasks on levels 2 and 5, level 9 is never delayed by it
+//! static SAMPLES: Channel<CeilingRawMutex<5>, u32, 8> = Channel::new();
+//! ```
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+
+use crate::executor::{Priority, MAX_LEVELS};
+use crate::interrupt;
+use crate::soft_irq;
+
+/// Run `f` with all levels up to `ceiling` held back.
+///
+/// Panics if called from a higher level or from an interrupt handler.
+pub fn with<R>(ceiling: Priority, f: impl FnOnce() -> R) -> R {
+    let level = ceiling.as_level();
+    if interrupt::in_handler() {
+        panic!("Ceiling {} locked from an interrupt or exception handler", level);
+    }
+    if let Some(current) = soft_irq::running_level() {
+        if current > level {
+            panic!("Ceiling {} locked from higher level {}", level, current);
+        }
+    }
+
+    let raised = soft_irq::raise(level);
+    let result = f();
+    if raised {
+        soft_irq::lower_ceiling(level);
+    }
+    return result;
+}
+
+/// Raw mutex locking by raising the active level to `CEILING`, see the module
+/// documentation.
+pub struct CeilingRawMutex<const CEILING: usize> {
+    _private: (),
+}
+
+unsafe impl<const CEILING: usize> Send for CeilingRawMutex<CEILING> {}
+unsafe impl<const CEILING: usize> Sync for CeilingRawMutex<CEILING> {}
+
+impl<const CEILING: usize> CeilingRawMutex<CEILING> {
+    pub const fn new() -> Self {
+        const { assert!(CEILING < MAX_LEVELS, "ceiling out of range, see MAX_LEVELS") };
+        Self { _private: () }
+    }
+}
+
+unsafe impl<const CEILING: usize> RawMutex for CeilingRawMutex<CEILING> {
+    const INIT: Self = Self::new();
+
+    fn lock<R>(&self, f: impl FnOnce() -> R) -> R {
+        with(Priority::level::<CEILING>(), f)
+    }
+}
+
+/// Blocking mutex with a priority ceiling of `CEILING`.
+pub type CeilingMutex<const CEILING: usize, T> = embassy_sync::blocking_mutex::Mutex<CeilingRawMutex<CEILING>, T>;
@@ -0,0 +1,281 @@
+//! Crash record kept in RAM across resets.
+//!
+//! With the `crash-record` feature the panic and exception handlers store a
+//! [`CrashRecord`] with the message, the registers of an exception, the
+//! backtrace, the uptime and the executor level in the `.uninit` section of
+//! `link.x`. riscv-rt neither zeroes nor loads that section, so the record
+//! survives a reset which keeps the RAM powered, like the soft reset of the
+//! LiteX ctrl core or a watchdog reset.
+//!
+//! A magic word and a CRC tell a valid record from random memory after power
+//! up. On the next boot the application checks for it:
+//!
+//! ```ignore
+//! if let Some(record) = embassy_litex::crash::take() {
+//!     record.print();
+//! }
+//! ```
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::ptr::{addr_of, addr_of_mut};
+
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::backtrace::{self, MAX_DEPTH};
+use crate::serial::{self, print_fmt_func};
+use crate::trace::TrapFrame;
+use crate::{soft_irq, timer};
+
+/// Bytes of the message kept, longer messages are cut.
+pub const MESSAGE_SIZE: usize = 160;
+
+const MAGIC: u32 = 0xC0A5_4ED5;
+/// Level of a crash in thread mode.
+const THREAD_MODE: u32 = u32::MAX;
+
+/// What stopped the CPU.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CrashKind {
+    Panic,
+    /// An exception, the registers are in [`CrashRecord::frame`].
+    Exception,
+}
+
+/// A crash of a previous boot.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CrashRecord {
+    magic: u32,
+    crc: u32,
+    kind: u32,
+    level: u32,
+    uptime_cycles: u64,
+    frame: TrapFrame,
+    message_len: u32,
+    message: [u8; MESSAGE_SIZE],
+    depth: u32,
+    backtrace: [usize; MAX_DEPTH],
+}
+
+struct Storage(UnsafeCell<MaybeUninit<CrashRecord>>);
+
+unsafe impl Sync for Storage {}
+
+#[link_section = ".uninit.embassy_litex_crash"]
+static RECORD: Storage = Storage(UnsafeCell::new(MaybeUninit::uninit()));
+
+/// Set once a crash of this boot is recorded, a panic following an exception
+/// only adds its message.
+static RECORDED: AtomicBool = AtomicBool::new(false);
+
+
+/// Bitwise CRC-32 (IEEE) step, small and fast enough for one record.
+fn crc32_update(mut crc: u32, byte: u8) -> u32 {
+    crc ^= byte as u32;
+    for _ in 0..8 {
+        let mask = (crc & 0x1).wrapping_neg();
+        crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+    }
+    return crc;
+}
+
+/// The record in `.uninit`, possibly never written since power up.
+fn record_ptr() -> *mut CrashRecord {
+    RECORD.0.get() as *mut CrashRecord
+}
+
+/// CRC over everything following the `crc` field, read in place byte by byte
+/// as the memory may not hold a record.
+fn checksum(record: *const CrashRecord) -> u32 {
+    let bytes = record as *const u8;
+    let mut crc = 0xFFFF_FFFFu32;
+    for offset in 8..core::mem::size_of::<CrashRecord>() {
+        crc = crc32_update(crc, unsafe { bytes.add(offset).read_volatile() });
+    }
+    return !crc;
+}
+
+fn is_valid(record: *const CrashRecord) -> bool {
+    unsafe {
+        addr_of!((*record).magic).read_volatile() == MAGIC
+            && addr_of!((*record).message_len).read_volatile() as usize <= MESSAGE_SIZE
+            && addr_of!((*record).depth).read_volatile() as usize <= MAX_DEPTH
+            && addr_of!((*record).crc).read_volatile() == checksum(record)
+    }
+}
+
+impl CrashRecord {
+    pub fn kind(&self) -> CrashKind {
+        if self.kind == CrashKind::Exception as u32 {
+            return CrashKind::Exception;
+        }
+        return CrashKind::Panic;
+    }
+
+    /// Executor level of the crash, `None` in thread mode.
+    pub fn level(&self) -> Option<usize> {
+        if self.level == THREAD_MODE {
+            return None;
+        }
+        return Some(self.level as usize);
+    }
+
+    /// Time since boot in `sys_clk` cycles.
+    pub fn uptime_cycles(&self) -> u64 {
+        self.uptime_cycles
+    }
+
+    /// Time since boot in microseconds.
+    pub fn uptime_micros(&self) -> u64 {
+        timer::scale_down(self.uptime_cycles, timer::CLOCK_HZ, 1_000_000)
+    }
+
+    /// Panic message, cut at [`MESSAGE_SIZE`] bytes.
+    pub fn message(&self) -> &str {
+        let bytes = &self.message[..self.message_len as usize];
+        match core::str::from_utf8(bytes) {
+            Ok(message) => message,
+            // cut in the middle of a character
+            Err(e) => unsafe { core::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+        }
+    }
+
+    /// Registers at the exception.
+    pub fn frame(&self) -> Option<&TrapFrame> {
+        if self.kind() == CrashKind::Exception {
+            return Some(&self.frame);
+        }
+        return None;
+    }
+
+    /// Return addresses of the frames, innermost first.
+    pub fn backtrace(&self) -> &[usize] {
+        &self.backtrace[..self.depth as usize]
+    }
+
+    /// Print the record in the format of the panic handler, for the symbolize tool.
+    pub fn print(&self) {
+        serial::println("");
+        serial::println("-------------- Crash record ------------");
+        let micros = self.uptime_micros();
+        match self.level() {
+            Some(level) => print_fmt_func(format_args!("{:?} after {}.{:06} s on level {}\r\n",
+                self.kind(), micros / 1_000_000, micros % 1_000_000, level)),
+            None => print_fmt_func(format_args!("{:?} after {}.{:06} s in thread mode\r\n",
+                self.kind(), micros / 1_000_000, micros % 1_000_000)),
+        }
+        print_fmt_func(format_args!("{}\r\n", self.message()));
+        if let Some(frame) = self.frame() {
+            frame.print();
+        }
+        print_fmt_func(format_args!("Backtrace:\r\n"));
+        for (n, pc) in self.backtrace().iter().enumerate() {
+            print_fmt_func(format_args!("#{} pc={:#010x}\r\n", n, pc));
+        }
+        serial::println("End of crash record.");
+    }
+}
+
+/// Appends to the message buffer, dropping what does not fit.
+struct MessageWriter<'a> {
+    record: &'a mut CrashRecord,
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.record.message_len as usize;
+        let count = s.len().min(MESSAGE_SIZE - len);
+        self.record.message[len..len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.record.message_len = (len + count) as u32;
+        return Ok(());
+    }
+}
+
+/// Write a complete new record, false if one of this boot is already stored.
+///
+/// Only called by the crash handlers with interrupts disabled.
+fn begin(kind: CrashKind, fp: usize, frame: Option<&TrapFrame>) -> bool {
+    if RECORDED.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    let mut backtrace = [0; MAX_DEPTH];
+    let depth = backtrace::walk(fp, |n, pc| backtrace[n] = pc);
+    let record = CrashRecord {
+        magic: 0,
+        crc: 0,
+        kind: kind as u32,
+        level: soft_irq::running_level().map_or(THREAD_MODE, |level| level as u32),
+        uptime_cycles: timer::uptime_clk(),
+        // all registers are plain words
+        frame: frame.copied().unwrap_or(unsafe { core::mem::zeroed() }),
+        message_len: 0,
+        message: [0; MESSAGE_SIZE],
+        depth: depth as u32,
+        backtrace,
+    };
+    unsafe { record_ptr().write_volatile(record) };
+    return true;
+}
+
+fn seal() {
+    let record = record_ptr();
+    unsafe {
+        addr_of_mut!((*record).magic).write_volatile(MAGIC);
+        addr_of_mut!((*record).crc).write_volatile(checksum(record));
+    }
+}
+
+/// Called by the exception handler before it panics.
+pub(crate) fn record_exception(frame: &TrapFrame) {
+    if begin(CrashKind::Exception, frame.s0, Some(frame)) {
+        seal();
+    }
+}
+
+/// Called by the panic handler with its frame pointer.
+pub(crate) fn record_panic(info: &core::panic::PanicInfo, fp: usize) {
+    let fresh = begin(CrashKind::Panic, fp, None);
+    // written completely by `begin` during this boot either way
+    let record = unsafe { &mut *record_ptr() };
+    // otherwise an exception is recorded already, it gets the panic message
+    if !fresh && (record.magic != MAGIC || record.message_len != 0) {
+        return;
+    }
+
+    #[cfg(not(feature = "panic-minimal"))]
+    let _ = write!(MessageWriter { record: &mut *record }, "{}", info);
+    // only the location, without core::fmt
+    #[cfg(feature = "panic-minimal")]
+    if let Some(location) = info.location() {
+        let mut writer = MessageWriter { record: &mut *record };
+        let _ = writer.write_str(location.file());
+        let _ = writer.write_str(":");
+        let _ = writer.write_str(crate::panic::minimal::decimal(location.line(), &mut [0; 10]));
+    }
+    seal();
+}
+
+/// The crash record of a previous boot, if there is a valid one.
+pub fn read() -> Option<CrashRecord> {
+    let record = record_ptr();
+    if !is_valid(record) {
+        return None;
+    }
+    return Some(unsafe { record.read_volatile() });
+}
+
+/// Invalidate the stored record.
+pub fn clear() {
+    unsafe { addr_of_mut!((*record_ptr()).magic).write_volatile(0) };
+}
+
+/// Return and clear the crash record of a previous boot.
+pub fn take() -> Option<CrashRecord> {
+    let record = read();
+    if record.is_some() {
+        clear();
+    }
+    return record;
+}
@@ -0,0 +1,64 @@
+//! Delays for `embedded-hal` drivers.
+
+use embassy_time::Timer;
+
+use crate::timer::{self, scale_up, CLOCK_HZ};
+
+/// Delay provider for `embedded-hal` and `embedded-hal-async` drivers.
+///
+/// The blocking implementation spins on the `sys_clk` cycle counter and keeps
+/// the CPU busy, the async implementation goes through the embassy time queue
+/// and lets other tasks run.
+#[derive(Clone, Copy, Default)]
+pub struct Delay;
+
+impl Delay {
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+/// Busy wait for at least `cycles` `sys_clk` cycles.
+///
+/// Meant for sub-microsecond timing like bit-banging, the few cycles of call
+/// overhead are part of the wait.
+#[inline(always)]
+pub fn delay_cycles(cycles: u64) {
+    let start = timer::uptime_clk();
+    while timer::uptime_clk().wrapping_sub(start) < cycles {
+    }
+}
+
+/// Number of `sys_clk` cycles for `ns` nanoseconds, rounded up.
+#[inline(always)]
+pub const fn ns_to_cycles(ns: u64) -> u64 {
+    scale_up(ns, 1_000_000_000, CLOCK_HZ)
+}
+
+impl embedded_hal::delay::DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        delay_cycles(ns_to_cycles(ns as u64));
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        delay_cycles(scale_up(us as u64, 1_000_000, CLOCK_HZ));
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        delay_cycles(scale_up(ms as u64, 1_000, CLOCK_HZ));
+    }
+}
+
+impl embedded_hal_async::delay::DelayNs for Delay {
+    async fn delay_ns(&mut self, ns: u32) {
+        Timer::after_nanos(ns as u64).await
+    }
+
+    async fn delay_us(&mut self, us: u32) {
+        Timer::after_micros(us as u64).await
+    }
+
+    async fn delay_ms(&mut self, ms: u32) {
+        Timer::after_millis(ms as u64).await
+    }
+}
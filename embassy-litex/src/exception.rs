@@ -0,0 +1,397 @@
+//! Exception handling.
+//!
+//! [`install`] points `mtvec` to a trap entry in front of the one of riscv-rt.
+//! Interrupts are passed on to riscv-rt unchanged. For exceptions all
+//! registers are saved into a [`TrapFrame`] on a separate exception stack, so
+//! even a stack overflow into the PMP guard can be reported.
+//!
+//! A hook installed with [`set_hook`] for the exception gets the frame first.
+//! If it returns true the registers of the frame are restored and execution
+//! continues at `frame.pc`, e.g. after skipping the faulting instruction with
+//! [`skip_instruction`]. Otherwise the exception is printed with the decoded
+//! `mcause`, `mtval`, the faulting instruction and all registers, followed by
+//! a panic.
+//!
+//! riscv-rt has to use direct mode `mtvec`, the default.
+
+use core::arch::{asm, global_asm};
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::serial;
+#[cfg(not(feature = "panic-minimal"))]
+use crate::serial::print_fmt_func;
+use crate::trace::TrapFrame;
+
+/// Size of the stack exception hooks and the report run on.
+pub const EXCEPTION_STACK_SIZE: usize = 2048;
+
+/// Synchronous exceptions by their `mcause` code.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Exception {
+    InstructionMisaligned = 0,
+    InstructionFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadMisaligned = 4,
+    LoadFault = 5,
+    StoreMisaligned = 6,
+    StoreFault = 7,
+    UserEnvCall = 8,
+    SupervisorEnvCall = 9,
+    MachineEnvCall = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl Exception {
+    pub fn from_mcause(mcause: usize) -> Option<Self> {
+        let exception = match mcause {
+            0 => Exception::InstructionMisaligned,
+            1 => Exception::InstructionFault,
+            2 => Exception::IllegalInstruction,
+            3 => Exception::Breakpoint,
+            4 => Exception::LoadMisaligned,
+            5 => Exception::LoadFault,
+            6 => Exception::StoreMisaligned,
+            7 => Exception::StoreFault,
+            8 => Exception::UserEnvCall,
+            9 => Exception::SupervisorEnvCall,
+            11 => Exception::MachineEnvCall,
+            12 => Exception::InstructionPageFault,
+            13 => Exception::LoadPageFault,
+            15 => Exception::StorePageFault,
+            _ => return None,
+        };
+        return Some(exception);
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Exception::InstructionMisaligned => "Instruction address misaligned",
+            Exception::InstructionFault => "Instruction access fault",
+            Exception::IllegalInstruction => "Illegal instruction",
+            Exception::Breakpoint => "Breakpoint",
+            Exception::LoadMisaligned => "Load address misaligned",
+            Exception::LoadFault => "Load access fault",
+            Exception::StoreMisaligned => "Store/AMO address misaligned",
+            Exception::StoreFault => "Store/AMO access fault",
+            Exception::UserEnvCall => "Environment call from U-mode",
+            Exception::SupervisorEnvCall => "Environment call from S-mode",
+            Exception::MachineEnvCall => "Environment call from M-mode",
+            Exception::InstructionPageFault => "Instruction page fault",
+            Exception::LoadPageFault => "Load page fault",
+            Exception::StorePageFault => "Store/AMO page fault",
+        }
+    }
+
+    /// Meaning of `mtval` for this exception.
+    fn mtval_name(self) -> &'static str {
+        match self {
+            Exception::IllegalInstruction => "instruction",
+            Exception::Breakpoint | Exception::InstructionMisaligned | Exception::InstructionFault
+            | Exception::InstructionPageFault => "pc",
+            Exception::LoadMisaligned | Exception::LoadFault | Exception::StoreMisaligned
+            | Exception::StoreFault | Exception::LoadPageFault | Exception::StorePageFault => "address",
+            _ => "value",
+        }
+    }
+
+    /// True if `pc` may not point to readable code.
+    fn is_fetch_fault(self) -> bool {
+        matches!(self, Exception::InstructionMisaligned | Exception::InstructionFault | Exception::InstructionPageFault)
+    }
+}
+
+/// Called with the registers at the exception, returns true to resume at `frame.pc`.
+pub type ExceptionHook = fn(frame: &mut TrapFrame) -> bool;
+
+const MAX_CAUSE: usize = 16;
+
+static HOOKS: Mutex<Cell<[Option<ExceptionHook>; MAX_CAUSE]>> = Mutex::new(Cell::new([None; MAX_CAUSE]));
+static IN_EXCEPTION: AtomicBool = AtomicBool::new(false);
+
+
+/// Install `hook` for `exception`, replacing a previous one.
+pub fn set_hook(exception: Exception, hook: ExceptionHook) {
+    critical_section::with(|cs| {
+        let cell = HOOKS.borrow(cs);
+        let mut hooks = cell.get();
+        hooks[exception as usize] = Some(hook);
+        cell.set(hooks);
+    });
+}
+
+pub fn clear_hook(exception: Exception) {
+    critical_section::with(|cs| {
+        let cell = HOOKS.borrow(cs);
+        let mut hooks = cell.get();
+        hooks[exception as usize] = None;
+        cell.set(hooks);
+    });
+}
+
+/// Length in bytes of the instruction at `pc`, 2 for compressed instructions.
+pub fn instruction_length(pc: usize) -> usize {
+    let low = unsafe { (pc as *const u16).read_volatile() };
+    if (low & 0x3) == 0x3 {
+        return 4;
+    }
+    return 2;
+}
+
+/// Continue after the faulting instruction, for hooks returning true.
+pub fn skip_instruction(frame: &mut TrapFrame) {
+    frame.pc += instruction_length(frame.pc);
+}
+
+/// Lower and upper end of the exception stack.
+pub fn stack_bounds() -> (usize, usize) {
+    extern "C" {
+        static _embassy_litex_exception_stack: u8;
+        static _embassy_litex_exception_stack_top: u8;
+    }
+    return (
+        core::ptr::addr_of!(_embassy_litex_exception_stack) as usize,
+        core::ptr::addr_of!(_embassy_litex_exception_stack_top) as usize,
+    );
+}
+
+/// Point `mtvec` to the exception entry.
+///
+/// Called by the thread-mode executor before anything else at boot.
+pub fn install() {
+    unsafe {
+        asm!("la {0}, _embassy_litex_trap", "csrw mtvec, {0}", out(reg) _);
+    }
+}
+
+#[cfg(not(feature = "panic-minimal"))]
+fn print_instruction(frame: &TrapFrame, exception: Exception) {
+    if exception.is_fetch_fault() {
+        return;
+    }
+    // halfword reads, the instruction may only be 2 byte aligned
+    let low = unsafe { (frame.pc as *const u16).read_volatile() } as u32;
+    if (low & 0x3) != 0x3 {
+        print_fmt_func(format_args!("Instruction: {:#06x} (compressed)\r\n", low));
+        return;
+    }
+    let high = unsafe { ((frame.pc + 2) as *const u16).read_volatile() } as u32;
+    print_fmt_func(format_args!("Instruction: {:#010x}\r\n", (high << 16) | low));
+}
+
+/// Print the decoded exception and the registers of `frame`.
+#[cfg(not(feature = "panic-minimal"))]
+pub fn report(frame: &TrapFrame) {
+    serial::println("");
+    serial::println("-------------- Exception ------------");
+    match Exception::from_mcause(frame.mcause) {
+        Some(exception) => {
+            print_fmt_func(format_args!("{} (mcause {}) at pc {:#010x}\r\n", exception.name(), frame.mcause, frame.pc));
+            print_fmt_func(format_args!("mtval ({}): {:#010x}\r\n", exception.mtval_name(), frame.mtval));
+            print_instruction(frame, exception);
+        }
+        None => {
+            print_fmt_func(format_args!("Unknown exception (mcause {}) at pc {:#010x}\r\n", frame.mcause, frame.pc));
+            print_fmt_func(format_args!("mtval: {:#010x}\r\n", frame.mtval));
+        }
+    }
+    #[cfg(feature = "pmp")]
+    crate::pmp::report_fault(frame.mcause, frame.pc, frame.mtval);
+    frame.print();
+}
+
+/// Print the exception and the registers of `frame` without `core::fmt`.
+#[cfg(feature = "panic-minimal")]
+pub fn report(frame: &TrapFrame) {
+    use crate::panic::minimal::{print_dec, print_hex};
+
+    serial::println("");
+    serial::println("-------------- Exception ------------");
+    serial::print(Exception::from_mcause(frame.mcause).map_or("Unknown exception", |e| e.name()));
+    serial::print(" (mcause ");
+    print_dec(frame.mcause as u32);
+    serial::print(") at pc ");
+    print_hex(frame.pc);
+    serial::println("");
+    serial::print("mtval: ");
+    print_hex(frame.mtval);
+    serial::println("");
+    frame.print();
+}
+
+#[no_mangle]
+extern "C" fn _embassy_litex_exception(frame: &mut TrapFrame) {
+    // `ebreak` of the panic action without a debugger, keep looping over it
+    // so a debugger attached later still stops there
+    #[cfg(all(feature = "panic-handler", feature = "panic-ebreak"))]
+    if frame.mcause == Exception::Breakpoint as usize && crate::panic::is_panicking() {
+        skip_instruction(frame);
+        return;
+    }
+
+    if IN_EXCEPTION.swap(true, Ordering::Relaxed) {
+        #[cfg(not(feature = "panic-minimal"))]
+        print_fmt_func(format_args!("\r\nException {} at pc {:#010x} in the exception handler\r\n", frame.mcause, frame.pc));
+        #[cfg(feature = "panic-minimal")]
+        {
+            use crate::panic::minimal::{print_dec, print_hex};
+
+            serial::println("");
+            serial::print("Exception ");
+            print_dec(frame.mcause as u32);
+            serial::print(" at pc ");
+            print_hex(frame.pc);
+            serial::println(" in the exception handler");
+        }
+        loop {}
+    }
+
+    let hook = if frame.mcause < MAX_CAUSE {
+        critical_section::with(|cs| HOOKS.borrow(cs).get()[frame.mcause])
+    } else {
+        None
+    };
+    if let Some(hook) = hook {
+        if crate::interrupt::as_handler(|| hook(frame)) {
+            IN_EXCEPTION.store(false, Ordering::Relaxed);
+            return;
+        }
+    }
+
+    #[cfg(feature = "crash-record")]
+    crate::crash::record_exception(frame);
+    report(frame);
+    // the panic handler takes the panic action, the frame pointer chain leads back into the faulting code
+    #[cfg(not(feature = "panic-minimal"))]
+    {
+        let name = Exception::from_mcause(frame.mcause).map_or("Unknown exception", |e| e.name());
+        panic!("{} at pc {:#010x}", name, frame.pc);
+    }
+    #[cfg(feature = "panic-minimal")]
+    panic!("exception");
+}
+
+
+// Exceptions save all registers in TrapFrame order on the exception stack
+// (kept if already on it) and return to `frame.pc` with `frame.mstatus`.
+// Interrupts go to riscv-rt with all registers untouched.
+global_asm!(
+    ".section .trap, \"ax\"",
+    ".align 2",
+    ".global _embassy_litex_trap",
+    "_embassy_litex_trap:",
+    "    csrrw t0, mscratch, t0",
+    "    csrr t0, mcause",
+    "    bltz t0, 4f",
+
+    "    la t0, _embassy_litex_exception_stack_top",
+    "    sw t1, -4(t0)",
+    "    sw sp, -8(t0)",
+    "    la t1, _embassy_litex_exception_stack",
+    "    bltu sp, t1, 1f",
+    "    bgeu sp, t0, 1f",
+    "    j 2f",
+    "1:  addi sp, t0, -16",
+    "2:  addi sp, sp, -{frame_size}",
+
+    "    sw ra, 0(sp)",
+    "    csrr t1, mscratch",
+    "    sw t1, 4(sp)",
+    "    lw t1, -4(t0)",
+    "    sw t1, 8(sp)",
+    "    sw t2, 12(sp)",
+    "    sw t3, 16(sp)",
+    "    sw t4, 20(sp)",
+    "    sw t5, 24(sp)",
+    "    sw t6, 28(sp)",
+    "    sw a0, 32(sp)",
+    "    sw a1, 36(sp)",
+    "    sw a2, 40(sp)",
+    "    sw a3, 44(sp)",
+    "    sw a4, 48(sp)",
+    "    sw a5, 52(sp)",
+    "    sw a6, 56(sp)",
+    "    sw a7, 60(sp)",
+    "    sw s0, 64(sp)",
+    "    sw s1, 68(sp)",
+    "    sw s2, 72(sp)",
+    "    sw s3, 76(sp)",
+    "    sw s4, 80(sp)",
+    "    sw s5, 84(sp)",
+    "    sw s6, 88(sp)",
+    "    sw s7, 92(sp)",
+    "    sw s8, 96(sp)",
+    "    sw s9, 100(sp)",
+    "    sw s10, 104(sp)",
+    "    sw s11, 108(sp)",
+    "    sw gp, 112(sp)",
+    "    sw tp, 116(sp)",
+    "    lw t1, -8(t0)",
+    "    sw t1, 120(sp)",
+    "    csrr t1, mepc",
+    "    sw t1, 124(sp)",
+    "    csrr t1, mstatus",
+    "    sw t1, 128(sp)",
+    "    csrr t1, mcause",
+    "    sw t1, 132(sp)",
+    "    csrr t1, mtval",
+    "    sw t1, 136(sp)",
+
+    "    mv a0, sp",
+    "    call _embassy_litex_exception",
+
+    "    lw t1, 124(sp)",
+    "    csrw mepc, t1",
+    "    lw t1, 128(sp)",
+    "    csrw mstatus, t1",
+    "    lw ra, 0(sp)",
+    "    lw t0, 4(sp)",
+    "    lw t1, 8(sp)",
+    "    lw t2, 12(sp)",
+    "    lw t3, 16(sp)",
+    "    lw t4, 20(sp)",
+    "    lw t5, 24(sp)",
+    "    lw t6, 28(sp)",
+    "    lw a0, 32(sp)",
+    "    lw a1, 36(sp)",
+    "    lw a2, 40(sp)",
+    "    lw a3, 44(sp)",
+    "    lw a4, 48(sp)",
+    "    lw a5, 52(sp)",
+    "    lw a6, 56(sp)",
+    "    lw a7, 60(sp)",
+    "    lw s0, 64(sp)",
+    "    lw s1, 68(sp)",
+    "    lw s2, 72(sp)",
+    "    lw s3, 76(sp)",
+    "    lw s4, 80(sp)",
+    "    lw s5, 84(sp)",
+    "    lw s6, 88(sp)",
+    "    lw s7, 92(sp)",
+    "    lw s8, 96(sp)",
+    "    lw s9, 100(sp)",
+    "    lw s10, 104(sp)",
+    "    lw s11, 108(sp)",
+    "    lw gp, 112(sp)",
+    "    lw tp, 116(sp)",
+    "    lw sp, 120(sp)",
+    "    mret",
+
+    "4:  csrrw t0, mscratch, t0",
+    "    j _start_trap",
+
+    ".section .bss.embassy_litex_exception_stack, \"aw\", @nobits",
+    ".align 4",
+    ".global _embassy_litex_exception_stack",
+    ".global _embassy_litex_exception_stack_top",
+    "_embassy_litex_exception_stack:",
+    "    .space {stack_size}",
+    "_embassy_litex_exception_stack_top:",
+    frame_size = const (core::mem::size_of::<TrapFrame>() + 15) & !15,
+    stack_size = const EXCEPTION_STACK_SIZE,
+);
@@ -8,10 +8,15 @@ use embassy_executor::SendSpawner;
 use embassy_executor::Spawner;
 use embassy_executor::raw;
 
-use crate::soc_headers;
-use crate::soc_headers::CSR_CPU_SOFTWARE_INTERRUPT_REQUEST_INTERRUPT_ADDR;
-use crate::register::Register;
+use crate::idle;
+use crate::interrupt;
 use crate::serial;
+use crate::soft_irq;
+use crate::software_interrupt;
+#[cfg(feature = "executor-stats")]
+use crate::stats;
+#[cfg(feature = "poll-watchdog")]
+use crate::watchdog;
 use crate::serial::print_fmt_func;
 
 
@@ -25,6 +30,126 @@ pub use embassy_litex_macros::main as main;
 
 
 
+/// Context of the thread-mode executor, passed to `__pender`.
+const THREAD_MODE_CONTEXT: usize = usize::MAX;
+
+static SIGNAL_WORK_THREAD_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Returns true and clears the flag if the thread-mode executor has been woken.
+pub(crate) fn take_thread_signal() -> bool {
+    return SIGNAL_WORK_THREAD_MODE.swap(false, Ordering::AcqRel);
+}
+
+/// Own cycles of all finished interrupt executor polls, without the time they
+/// were preempted themselves. Grows during a section by exactly the time the
+/// section was preempted by higher levels.
+#[cfg(any(feature = "executor-stats", feature = "task-monitor", feature = "poll-watchdog"))]
+static PREEMPTED_CYCLES: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// Start of a section whose busy time excludes preemption by higher levels,
+/// shared by the executor stats, the task monitor and the poll watchdog.
+#[cfg(any(feature = "executor-stats", feature = "task-monitor", feature = "poll-watchdog"))]
+#[derive(Clone, Copy)]
+pub(crate) struct BusyStart {
+    start: u64,
+    preempted: u64,
+}
+
+#[cfg(any(feature = "executor-stats", feature = "task-monitor", feature = "poll-watchdog"))]
+impl BusyStart {
+    /// Placeholder for tables, not a valid start.
+    pub(crate) const NONE: Self = Self { start: 0, preempted: 0 };
+
+    pub(crate) fn now(cs: critical_section::CriticalSection) -> Self {
+        return Self { start: crate::timer::uptime_clk(), preempted: PREEMPTED_CYCLES.borrow(cs).get() };
+    }
+
+    /// Cycles since [`now`](Self::now) without the time spent in higher levels.
+    pub(crate) fn busy_cycles(&self, cs: critical_section::CriticalSection) -> u64 {
+        let preempted = PREEMPTED_CYCLES.borrow(cs).get() - self.preempted;
+        return (crate::timer::uptime_clk() - self.start).saturating_sub(preempted);
+    }
+}
+
+/// Thread-mode executor.
+///
+/// Runs in the `main` loop below all interrupt executor levels. Tasks are
+/// polled outside of any trap context, so they may block for a long time
+/// without delaying other interrupts. The CPU sleeps in [`idle::sleep`]
+/// whenever no task is ready.
+pub struct ThreadExecutor {
+    executor: UnsafeCell<MaybeUninit<raw::Executor>>,
+    started: Mutex<Cell<bool>>,
+}
+
+unsafe impl Send for ThreadExecutor {}
+unsafe impl Sync for ThreadExecutor {}
+
+/// The thread-mode executor, started by the `main` macro.
+pub static THREAD_EXECUTOR: ThreadExecutor = ThreadExecutor::new();
+
+impl ThreadExecutor {
+    const fn new() -> Self {
+        Self {
+            executor: UnsafeCell::new(MaybeUninit::uninit()),
+            started: Mutex::new(Cell::new(false)),
+        }
+    }
+
+    /// Run the executor, `init` is called once with its spawner.
+    ///
+    /// This never returns, it must be called from `main`.
+    pub fn run(&'static self, init: impl FnOnce(Spawner)) -> ! {
+        if critical_section::with(|cs| self.started.borrow(cs).replace(true)) {
+            panic!("ThreadExecutor::run() called multiple times.");
+        }
+
+        unsafe {
+            (&mut *self.executor.get())
+                .as_mut_ptr()
+                .write(raw::Executor::new(THREAD_MODE_CONTEXT as *mut ()))
+        }
+        let executor = unsafe { (&*self.executor.get()).assume_init_ref() };
+
+        crate::exception::install();
+        #[cfg(feature = "stack-paint")]
+        crate::stack::paint();
+        #[cfg(feature = "pmp")]
+        crate::pmp::init();
+        #[cfg(feature = "fpu")]
+        crate::fpu::init();
+
+        init(executor.spawner());
+
+        loop {
+            unsafe {
+                executor.poll();
+            }
+            #[cfg(feature = "stack-paint")]
+            crate::stack::check();
+            idle::sleep();
+        }
+    }
+
+    /// Get a SendSpawner for the thread-mode executor
+    ///
+    /// This MUST only be called after the executor has been started.
+    /// The function will panic otherwise.
+    pub fn send_spawner(&'static self) -> SendSpawner {
+        if !critical_section::with(|cs| self.started.borrow(cs).get()) {
+            panic!("ThreadExecutor::send_spawner() called on uninitialized executor.");
+        }
+        let executor = unsafe { (&*self.executor.get()).assume_init_ref() };
+        executor.spawner().make_send()
+    }
+
+    /// Id of the executor in the embassy-executor trace hooks.
+    pub(crate) fn id(&self) -> usize {
+        self.executor.get() as usize
+    }
+}
+
+
 /// Interrupt mode executor.
 ///
 /// This executor runs tasks in interrupt mode. The interrupt handler is set up
@@ -33,39 +158,91 @@ pub use embassy_litex_macros::main as main;
 pub struct InterruptExecutor {
     pub executor: UnsafeCell<MaybeUninit<raw::Executor>>,
     started: Mutex<Cell<bool>>,
-    context :usize
+    context :usize,
+    #[cfg(feature = "executor-stats")]
+    pub(crate) stats: stats::ExecutorStatsCell,
 }
 
-// TODO: initialize with function
-const MAX_LEVELS:usize = 16;
-pub static EXECUTOR_LIST: [InterruptExecutor;MAX_LEVELS] = [
-    InterruptExecutor::new( 0 ),
-    InterruptExecutor::new( 1 ),
-    InterruptExecutor::new( 2 ),
-    InterruptExecutor::new( 3 ),
+#[cfg(all(feature = "executor-levels-4", feature = "executor-levels-8"))]
+compile_error!("select only one of the `executor-levels-*` features");
+
+/// Number of interrupt executor levels, 16 unless reduced by an
+/// `executor-levels-*` feature. Each level uses one software interrupt bit.
+#[cfg(feature = "executor-levels-4")]
+pub const MAX_LEVELS:usize = 4;
+#[cfg(feature = "executor-levels-8")]
+pub const MAX_LEVELS:usize = 8;
+#[cfg(not(any(feature = "executor-levels-4", feature = "executor-levels-8")))]
+pub const MAX_LEVELS:usize = 16;
+
+pub static EXECUTOR_LIST: [InterruptExecutor;MAX_LEVELS] = executor_list();
+
+const fn executor_list() -> [InterruptExecutor;MAX_LEVELS] {
+    let mut list: [MaybeUninit<InterruptExecutor>;MAX_LEVELS] = [const { MaybeUninit::uninit() }; MAX_LEVELS];
+    let mut level = 0;
+    while level < MAX_LEVELS {
+        list[level] = MaybeUninit::new(InterruptExecutor::new(level));
+        level += 1;
+    }
+    // every element has been initialized above
+    unsafe { core::mem::transmute(list) }
+}
 
-    InterruptExecutor::new( 4 ),
-    InterruptExecutor::new( 5 ),
-    InterruptExecutor::new( 6 ),
-    InterruptExecutor::new( 7 ),
 
-    InterruptExecutor::new( 8 ),
-    InterruptExecutor::new( 9 ),
-    InterruptExecutor::new( 10 ),
-    InterruptExecutor::new( 11 ),
+/// Priority of an interrupt executor level.
+///
+/// Higher levels preempt lower ones, all of them preempt the thread-mode
+/// executor. Levels are checked against [`MAX_LEVELS`] at compile time.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Priority(usize);
+
+impl Priority {
+    pub const LOWEST: Priority = Priority(0);
+    pub const LOW: Priority = Priority(MAX_LEVELS / 4);
+    pub const MEDIUM: Priority = Priority(MAX_LEVELS / 2);
+    pub const HIGH: Priority = Priority(MAX_LEVELS * 3 / 4);
+    pub const HIGHEST: Priority = Priority(MAX_LEVELS - 1);
+
+    /// Priority of executor level `L`, fails to compile if `L >= MAX_LEVELS`.
+    pub const fn level<const L: usize>() -> Self {
+        const { assert!(L < MAX_LEVELS, "executor level out of range, see MAX_LEVELS") };
+        Priority(L)
+    }
 
-    InterruptExecutor::new( 12 ),
-    InterruptExecutor::new( 13 ),
-    InterruptExecutor::new( 14 ),
-    InterruptExecutor::new( 15 ),
-];
+    /// Priority of executor level `level` if it exists.
+    pub const fn from_level(level: usize) -> Option<Self> {
+        if level < MAX_LEVELS {
+            return Some(Priority(level));
+        }
+        return None;
+    }
+
+    pub const fn as_level(self) -> usize {
+        self.0
+    }
+}
+
+/// The interrupt executor running at `priority`.
+pub fn executor(priority: Priority) -> &'static InterruptExecutor {
+    &EXECUTOR_LIST[priority.as_level()]
+}
+
+/// Start the interrupt executor at `priority`, see [`InterruptExecutor::start`].
+pub fn start(priority: Priority) -> SendSpawner {
+    executor(priority).start()
+}
+
+/// Spawner of the already started executor at `priority`.
+pub fn send_spawner(priority: Priority) -> SendSpawner {
+    executor(priority).send_spawner()
+}
 
 
 
 use core::cell::{Cell};
 use critical_section::Mutex;
 
-use portable_atomic::{AtomicU32, Ordering};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
 
 
 unsafe impl Send for InterruptExecutor {}
@@ -84,84 +261,43 @@ impl InterruptExecutor {
             executor: UnsafeCell::new(MaybeUninit::uninit()),
             started: Mutex::new(Cell::new(false)),
             context:ctx,
+            #[cfg(feature = "executor-stats")]
+            stats: stats::ExecutorStatsCell::new(),
         }
     }
 
     pub fn on_interrupt(&'static self) {
-        // Floating point implementation on public litex repository missing
-        //unsafe {
-        //    core::arch::asm!(
-        //        "addi	sp, sp, -176",
-        //
-        //        "fsd f0, 0(sp)",
-        //        "fsd f1, 8(sp)",
-        //        "fsd f2, 16(sp)",
-        //        "fsd f3, 24(sp)",
-        //        "fsd f4, 32(sp)",
-        //        "fsd f5, 40(sp)",
-        //        "fsd f6, 48(sp)",
-        //        "fsd f7, 56(sp)",
-        //        "fsd f10, 64(sp)",
-        //        "fsd f11, 72(sp)",
-        //        "fsd f12, 80(sp)",
-        //        "fsd f13, 88(sp)",
-        //        "fsd f14, 96(sp)",
-        //        "fsd f15, 104(sp)",
-        //        "fsd f16, 112(sp)",
-        //        "fsd f17, 120(sp)",
-        //        "fsd f28, 128(sp)",
-        //        "fsd f29, 136(sp)",
-        //        "fsd f30, 144(sp)",
-        //        "fsd f31, 152(sp)",
-        //
-        //        "frcsr t0",
-        //        "sw t0,  160(sp)",
-        //
-        //        out("t0") _,
-        //        
-        //    );
-        //}
-        unsafe {
-            riscv::interrupt::enable();
-        }
+        // the FPU state of the preempted level is saved by `nested` with the `fpu` feature
         let executor = unsafe { (&*self.executor.get()).assume_init_ref() };
-        unsafe {
+
+        #[cfg(feature = "executor-stats")]
+        self.stats.poll_begin();
+        #[cfg(any(feature = "executor-stats", feature = "task-monitor", feature = "poll-watchdog"))]
+        let begin = critical_section::with(|cs| BusyStart::now(cs));
+        #[cfg(feature = "poll-watchdog")]
+        let previous_level = watchdog::enter(self.context);
+        #[cfg(feature = "stack-sampling")]
+        crate::stack::level_enter(self.context);
+
+        interrupt::nested(|| unsafe {
             executor.poll();
-        }
-        unsafe {
-            riscv::interrupt::disable();
-        }
-        //unsafe {
-        //    core::arch::asm!(
-        //        "fld f0, 0(sp)",
-        //        "fld f1, 8(sp)",
-        //        "fld f2, 16(sp)",
-        //        "fld f3, 24(sp)",
-        //        "fld f4, 32(sp)",
-        //        "fld f5, 40(sp)",
-        //        "fld f6, 48(sp)",
-        //        "fld f7, 56(sp)",
-        //        "fld f10, 64(sp)",
-        //        "fld f11, 72(sp)",
-        //        "fld f12, 80(sp)",
-        //        "fld f13, 88(sp)",
-        //        "fld f14, 96(sp)",
-        //        "fld f15, 104(sp)",
-        //        "fld f16, 112(sp)",
-        //        "fld f17, 120(sp)",
-        //        "fld f28, 128(sp)",
-        //        "fld f29, 136(sp)",
-        //        "fld f30, 144(sp)",
-        //        "fld f31, 152(sp)",
-        //
-        //        "lw t0,  160(sp)",
-        //        "fscsr t0",
-        //        
-        //        "addi	sp, sp, 176",
-        //        
-        //        out("t0") _,
-        //    );
-        //}
+        });
+
+        #[cfg(feature = "stack-sampling")]
+        crate::stack::level_exit(self.context);
+        #[cfg(feature = "poll-watchdog")]
+        watchdog::exit(previous_level);
+        #[cfg(any(feature = "executor-stats", feature = "task-monitor", feature = "poll-watchdog"))]
+        #[cfg_attr(not(feature = "executor-stats"), allow(unused_variables))]
+        let busy = critical_section::with(|cs| {
+            let busy = begin.busy_cycles(cs);
+            // only the own cycles, nested polls already added theirs
+            let preempted = PREEMPTED_CYCLES.borrow(cs);
+            preempted.set(preempted.get() + busy);
+            return busy;
+        });
+        #[cfg(feature = "executor-stats")]
+        self.stats.poll_end(busy);
     }
 
     /// Start the executor at the given priority level.
@@ -179,9 +315,22 @@ impl InterruptExecutor {
     /// use [`Spawner::for_current_executor`](embassy_executor::Spawner::for_current_executor)
     /// from a task running in it.
     pub fn start(&'static self) -> SendSpawner {
-        if critical_section::with(|cs| self.started.borrow(cs).replace(true)) {
+        // checked and claimed together, `SoftwareInterrupt::register_interrupt`
+        // checks `started` in a critical section as well
+        let (started, reserved) = critical_section::with(|cs| {
+            let started = self.started.borrow(cs).get();
+            let reserved = software_interrupt::is_reserved(self.context);
+            if !started && !reserved {
+                self.started.borrow(cs).set(true);
+            }
+            return (started, reserved);
+        });
+        if started {
             panic!("InterruptExecutor::start() called multiple times on the same executor.");
         }
+        if reserved {
+            panic!("InterruptExecutor::start() called on a level reserved for a software interrupt.");
+        }
 
         unsafe {
             (&mut *self.executor.get())
@@ -210,6 +359,17 @@ impl InterruptExecutor {
     }
 
 
+    /// Returns true if [`start`](Self::start) has been called on this executor.
+    pub fn is_started(&self) -> bool {
+        critical_section::with(|cs| self.started.borrow(cs).get())
+    }
+
+    /// Id of the executor in the embassy-executor trace hooks.
+    pub(crate) fn id(&self) -> usize {
+        self.executor.get() as usize
+    }
+
+
     pub fn spawner(&'static self) -> Spawner {
         if !critical_section::with(|cs| self.started.borrow(cs).get()) {
             panic!("InterruptExecutor::spawner() called on uninitialized executor.");
@@ -225,50 +385,31 @@ impl InterruptExecutor {
 }
 
 
-const SOFTWARE_INTERRUPT_REQUEST_REGISTER:Register = Register { addr: soc_headers::CSR_CPU_SOFTWARE_INTERRUPT_REQUEST_INTERRUPT_ADDR };
-const SOFTWARE_INTERRUPT_ACTIVE_REGISTER:Register = Register { addr: soc_headers::CSR_CPU_SOFTWARE_INTERRUPT_ACTIVE_INTERRUPT_ADDR };
-const SOFTWARE_INTERRUPT_STATUS_REGISTER:Register = Register { addr: soc_headers::CSR_CPU_SOFTWARE_INTERRUPT_STATUS_INTERRUPT_ADDR };
-
-
-const CSR_OPERATION_SET:u32 = 0x8000_0000;
-const CSR_OPERATION_CLEAR:u32 = 0x4000_0000;
-const CSR_OPERATION_REPLACE:u32 = 0x0000_0000;
-
 #[export_name = "__pender"]
 fn __pender(context: *mut ()) {
     let context = context as usize;
     //serial::print_fmt_func(format_args!("EXE call pender: {}\r\n", context));
     if context < MAX_LEVELS{
-        let mut mask = CSR_OPERATION_SET + (0x1 << context);
-        SOFTWARE_INTERRUPT_REQUEST_REGISTER.write(mask);
+        #[cfg(feature = "executor-stats")]
+        EXECUTOR_LIST[context].stats.on_pend();
+
+        soft_irq::request(context);
+    } else if context == THREAD_MODE_CONTEXT {
+        SIGNAL_WORK_THREAD_MODE.store(true, Ordering::Release);
     }
 }
 
 #[allow(non_snake_case)]
 #[export_name = "MachineSoftInterruptHandler"]
 pub extern "Rust" fn MachineSoftInterruptHandler(level: u32, interrupt: usize) {
-    // get Executor number from CSR
-
-    let mut num: usize = usize::MAX;
-
-    critical_section::with(|_| {
-        // get the highest set bit from the interrupt register
-        // read Status
-        num = SOFTWARE_INTERRUPT_STATUS_REGISTER.read() as usize;
-
-        if num <= MAX_LEVELS {
-            SOFTWARE_INTERRUPT_REQUEST_REGISTER.write(CSR_OPERATION_CLEAR + (0x1 << num));
-            SOFTWARE_INTERRUPT_REQUEST_REGISTER.write(CSR_OPERATION_CLEAR + (0x1 << num));
-        }
-
-    });
-
-    if num <= MAX_LEVELS {
-
-            SOFTWARE_INTERRUPT_ACTIVE_REGISTER.write(CSR_OPERATION_SET + (0x1 << num));
+    // highest requested level above the active ones
+    if let Some(num) = soft_irq::take_highest() {
+        soft_irq::set_active(num);
+        if software_interrupt::is_reserved(num) {
+            software_interrupt::SoftwareInterrupt::execute_interrupt(num);
+        } else {
             EXECUTOR_LIST[num].on_interrupt();
-            SOFTWARE_INTERRUPT_ACTIVE_REGISTER.write(CSR_OPERATION_CLEAR + (0x1 << num));
-        
+        }
+        soft_irq::clear_active(num);
     }
-    
-}
\ No newline at end of file
+}
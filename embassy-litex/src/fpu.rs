@@ -0,0 +1,233 @@
+//! Lazy FPU context switching for F/D capable VexRiscv variants.
+//!
+//! The trap entry of riscv-rt only saves the integer registers. When a level
+//! preempts another one that used the FPU, the caller-saved float registers
+//! and `fcsr` of the preempted level are saved on the stack of the
+//! preempting handler and restored before returning. `mstatus.FS` tracks
+//! whether the FPU has been used since the last save, levels that never
+//! touched the FPU don't pay for the save.
+//!
+//! The callee-saved registers fs0-fs11 are preserved by the handlers
+//! themselves, as required by the calling convention.
+//!
+//! Only executor levels and software interrupts save the float state. External
+//! interrupt, timer alarm and exception handlers run with `mstatus.FS` off, a
+//! float instruction in them raises an illegal instruction exception instead
+//! of silently corrupting the registers of the preempted level.
+
+use core::arch::asm;
+
+#[cfg(not(target_feature = "f"))]
+compile_error!("the `fpu` feature needs a target with the F extension, e.g. riscv32imafc-unknown-none-elf");
+
+const MSTATUS_FS_MASK: usize = 0x3 << 13;
+const MSTATUS_FS_INITIAL: usize = 0x1 << 13;
+const MSTATUS_FS_CLEAN: usize = 0x2 << 13;
+const MSTATUS_FS_DIRTY: usize = 0x3 << 13;
+
+/// Caller-saved float registers f0-f7, f10-f17, f28-f31 and `fcsr`.
+#[repr(C)]
+pub(crate) struct FpuContext {
+    regs: [u64; 20],
+    fcsr: usize,
+}
+
+/// Turn the FPU on and reset its rounding mode and flags.
+pub fn init() {
+    unsafe {
+        asm!("csrc mstatus, {0}", in(reg) MSTATUS_FS_MASK);
+        asm!("csrs mstatus, {0}", in(reg) MSTATUS_FS_INITIAL);
+        asm!("fscsr x0");
+    }
+}
+
+/// Turn the FPU off for a handler without float state save, returns the
+/// previous `mstatus.FS` for [`enable`].
+#[inline(always)]
+pub(crate) fn disable() -> usize {
+    let mstatus: usize;
+    unsafe {
+        asm!("csrrc {0}, mstatus, {1}", out(reg) mstatus, in(reg) MSTATUS_FS_MASK);
+    }
+    return mstatus & MSTATUS_FS_MASK;
+}
+
+/// Restore `mstatus.FS` returned by [`disable`].
+#[inline(always)]
+pub(crate) fn enable(fs: usize) {
+    unsafe {
+        asm!("csrs mstatus, {0}", in(reg) fs);
+    }
+}
+
+/// Save the float state of the preempted level if it is dirty.
+///
+/// Must be called with interrupts disabled, at the start of a trap handler.
+#[inline(always)]
+pub(crate) fn save() -> Option<FpuContext> {
+    let mstatus: usize;
+    unsafe {
+        asm!("csrr {0}, mstatus", out(reg) mstatus);
+    }
+    if (mstatus & MSTATUS_FS_MASK) != MSTATUS_FS_DIRTY {
+        return None;
+    }
+
+    let mut context = FpuContext { regs: [0; 20], fcsr: 0 };
+    let ctx = context.regs.as_mut_ptr();
+    unsafe {
+        #[cfg(target_feature = "d")]
+        asm!(
+            "fsd f0, 0({ctx})",
+            "fsd f1, 8({ctx})",
+            "fsd f2, 16({ctx})",
+            "fsd f3, 24({ctx})",
+            "fsd f4, 32({ctx})",
+            "fsd f5, 40({ctx})",
+            "fsd f6, 48({ctx})",
+            "fsd f7, 56({ctx})",
+            "fsd f10, 64({ctx})",
+            "fsd f11, 72({ctx})",
+            "fsd f12, 80({ctx})",
+            "fsd f13, 88({ctx})",
+            "fsd f14, 96({ctx})",
+            "fsd f15, 104({ctx})",
+            "fsd f16, 112({ctx})",
+            "fsd f17, 120({ctx})",
+            "fsd f28, 128({ctx})",
+            "fsd f29, 136({ctx})",
+            "fsd f30, 144({ctx})",
+            "fsd f31, 152({ctx})",
+            ctx = in(reg) ctx,
+        );
+        #[cfg(not(target_feature = "d"))]
+        asm!(
+            "fsw f0, 0({ctx})",
+            "fsw f1, 8({ctx})",
+            "fsw f2, 16({ctx})",
+            "fsw f3, 24({ctx})",
+            "fsw f4, 32({ctx})",
+            "fsw f5, 40({ctx})",
+            "fsw f6, 48({ctx})",
+            "fsw f7, 56({ctx})",
+            "fsw f10, 64({ctx})",
+            "fsw f11, 72({ctx})",
+            "fsw f12, 80({ctx})",
+            "fsw f13, 88({ctx})",
+            "fsw f14, 96({ctx})",
+            "fsw f15, 104({ctx})",
+            "fsw f16, 112({ctx})",
+            "fsw f17, 120({ctx})",
+            "fsw f28, 128({ctx})",
+            "fsw f29, 136({ctx})",
+            "fsw f30, 144({ctx})",
+            "fsw f31, 152({ctx})",
+            ctx = in(reg) ctx,
+        );
+        asm!("frcsr {0}", out(reg) context.fcsr);
+
+        // a later save is only needed if the preempting level uses the FPU
+        asm!("csrc mstatus, {0}", in(reg) MSTATUS_FS_MASK);
+        asm!("csrs mstatus, {0}", in(reg) MSTATUS_FS_CLEAN);
+    }
+    return Some(context);
+}
+
+/// Restore the float state taken by [`save`].
+///
+/// Must be called with interrupts disabled, before the trap handler returns.
+#[inline(always)]
+pub(crate) fn restore(context: &FpuContext) {
+    let ctx = context.regs.as_ptr();
+    unsafe {
+        #[cfg(target_feature = "d")]
+        asm!(
+            "fld f0, 0({ctx})",
+            "fld f1, 8({ctx})",
+            "fld f2, 16({ctx})",
+            "fld f3, 24({ctx})",
+            "fld f4, 32({ctx})",
+            "fld f5, 40({ctx})",
+            "fld f6, 48({ctx})",
+            "fld f7, 56({ctx})",
+            "fld f10, 64({ctx})",
+            "fld f11, 72({ctx})",
+            "fld f12, 80({ctx})",
+            "fld f13, 88({ctx})",
+            "fld f14, 96({ctx})",
+            "fld f15, 104({ctx})",
+            "fld f16, 112({ctx})",
+            "fld f17, 120({ctx})",
+            "fld f28, 128({ctx})",
+            "fld f29, 136({ctx})",
+            "fld f30, 144({ctx})",
+            "fld f31, 152({ctx})",
+            ctx = in(reg) ctx,
+            out("ft0") _,
+            out("ft1") _,
+            out("ft2") _,
+            out("ft3") _,
+            out("ft4") _,
+            out("ft5") _,
+            out("ft6") _,
+            out("ft7") _,
+            out("fa0") _,
+            out("fa1") _,
+            out("fa2") _,
+            out("fa3") _,
+            out("fa4") _,
+            out("fa5") _,
+            out("fa6") _,
+            out("fa7") _,
+            out("ft8") _,
+            out("ft9") _,
+            out("ft10") _,
+            out("ft11") _,
+        );
+        #[cfg(not(target_feature = "d"))]
+        asm!(
+            "flw f0, 0({ctx})",
+            "flw f1, 8({ctx})",
+            "flw f2, 16({ctx})",
+            "flw f3, 24({ctx})",
+            "flw f4, 32({ctx})",
+            "flw f5, 40({ctx})",
+            "flw f6, 48({ctx})",
+            "flw f7, 56({ctx})",
+            "flw f10, 64({ctx})",
+            "flw f11, 72({ctx})",
+            "flw f12, 80({ctx})",
+            "flw f13, 88({ctx})",
+            "flw f14, 96({ctx})",
+            "flw f15, 104({ctx})",
+            "flw f16, 112({ctx})",
+            "flw f17, 120({ctx})",
+            "flw f28, 128({ctx})",
+            "flw f29, 136({ctx})",
+            "flw f30, 144({ctx})",
+            "flw f31, 152({ctx})",
+            ctx = in(reg) ctx,
+            out("ft0") _,
+            out("ft1") _,
+            out("ft2") _,
+            out("ft3") _,
+            out("ft4") _,
+            out("ft5") _,
+            out("ft6") _,
+            out("ft7") _,
+            out("fa0") _,
+            out("fa1") _,
+            out("fa2") _,
+            out("fa3") _,
+            out("fa4") _,
+            out("fa5") _,
+            out("fa6") _,
+            out("fa7") _,
+            out("ft8") _,
+            out("ft9") _,
+            out("ft10") _,
+            out("ft11") _,
+        );
+        asm!("fscsr {0}", in(reg) context.fcsr);
+    }
+}
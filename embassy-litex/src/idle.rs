@@ -0,0 +1,148 @@
+//! Tickless idle.
+//!
+//! The thread-mode executor started by [`executor::main`](crate::executor::main)
+//! calls [`sleep`] whenever none of its tasks is ready. Before
+//! sleeping the time until the next alarm of the time driver is used to pick
+//! an [`IdleMode`], the registered hooks get it to gate peripherals or to drop
+//! the UART into a quiet mode, and undo that after waking up.
+//!
+//! Hooks run with interrupts disabled, the interrupt which ended the sleep is
+//! taken after all post-wake hooks have returned.
+
+use core::cell::{Cell, RefCell};
+
+use critical_section::Mutex;
+use embassy_time::Duration;
+use embassy_time_driver::now;
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::executor;
+use crate::timer;
+
+/// Maximum number of registered idle hooks.
+pub const MAX_HOOKS: usize = 8;
+
+/// How deep the CPU is about to sleep.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum IdleMode {
+    /// Short sleep, only `wfi`.
+    Wfi,
+    /// The next alarm is at least the quiet threshold away, slow peripherals
+    /// like the UART may be quieted.
+    Quiet,
+    /// The next alarm is at least the deep threshold away or no alarm is
+    /// armed, peripherals may be gated.
+    Deep,
+}
+
+/// Called before sleeping with the mode and the ticks until the next alarm
+/// (`u64::MAX` if none).
+pub type PreSleepHook = fn(mode: IdleMode, ticks: u64);
+/// Called after waking up with the mode and the `sys_clk` cycles slept.
+pub type PostWakeHook = fn(mode: IdleMode, cycles: u64);
+
+#[derive(Clone, Copy)]
+struct IdleHook {
+    pre_sleep: PreSleepHook,
+    post_wake: PostWakeHook,
+}
+
+static HOOKS: Mutex<RefCell<[Option<IdleHook>; MAX_HOOKS]>> = Mutex::new(RefCell::new([None; MAX_HOOKS]));
+
+static QUIET_THRESHOLD: Mutex<Cell<Duration>> = Mutex::new(Cell::new(Duration::from_millis(1)));
+static DEEP_THRESHOLD: Mutex<Cell<Duration>> = Mutex::new(Cell::new(Duration::from_millis(100)));
+
+// no AtomicU64, its fallback lock deadlocks when a preempting level takes it
+static IDLE_CYCLES: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+static SLEEP_COUNT: AtomicU32 = AtomicU32::new(0);
+
+
+/// Register a pair of idle hooks, returns false if all slots are used.
+pub fn register_hook(pre_sleep: PreSleepHook, post_wake: PostWakeHook) -> bool {
+    critical_section::with(|cs| {
+        let mut hooks = HOOKS.borrow(cs).borrow_mut();
+        if let Some(slot) = hooks.iter_mut().find(|h| h.is_none()) {
+            *slot = Some(IdleHook { pre_sleep, post_wake });
+            return true;
+        }
+        return false;
+    })
+}
+
+/// Minimum time until the next alarm for [`IdleMode::Quiet`] and [`IdleMode::Deep`].
+pub fn set_thresholds(quiet: Duration, deep: Duration) {
+    critical_section::with(|cs| {
+        QUIET_THRESHOLD.borrow(cs).set(quiet);
+        DEEP_THRESHOLD.borrow(cs).set(deep);
+    });
+}
+
+/// Total `sys_clk` cycles spent sleeping.
+pub fn idle_cycles() -> u64 {
+    return critical_section::with(|cs| IDLE_CYCLES.borrow(cs).get());
+}
+
+/// Total time spent sleeping.
+pub fn idle_time() -> Duration {
+    return Duration::from_ticks(timer::cycles_to_ticks(idle_cycles()));
+}
+
+/// Number of times the CPU went to sleep.
+pub fn sleep_count() -> u32 {
+    return SLEEP_COUNT.load(Ordering::Relaxed);
+}
+
+fn select_mode(ticks: u64) -> IdleMode {
+    let (quiet, deep) = critical_section::with(|cs| (QUIET_THRESHOLD.borrow(cs).get(), DEEP_THRESHOLD.borrow(cs).get()));
+    if ticks >= deep.as_ticks() {
+        return IdleMode::Deep;
+    }
+    if ticks >= quiet.as_ticks() {
+        return IdleMode::Quiet;
+    }
+    return IdleMode::Wfi;
+}
+
+/// Sleep once until the next interrupt.
+///
+/// Returns at once if the thread-mode executor has been woken in the meantime.
+pub fn sleep() {
+    riscv::interrupt::disable();
+
+    if executor::take_thread_signal() {
+        unsafe {
+            riscv::interrupt::enable();
+        }
+        return;
+    }
+
+    let alarm = timer::next_alarm();
+    let ticks = if alarm == u64::MAX { u64::MAX } else { alarm.saturating_sub(now()) };
+    let mode = select_mode(ticks);
+    let hooks = critical_section::with(|cs| *HOOKS.borrow(cs).borrow());
+
+    for hook in hooks.iter().flatten() {
+        (hook.pre_sleep)(mode, ticks);
+    }
+
+    let start = timer::uptime_clk();
+    // wfi also returns on pending interrupts while they are globally disabled
+    unsafe {
+        core::arch::asm!("wfi");
+    }
+    let cycles = timer::uptime_clk() - start;
+
+    for hook in hooks.iter().rev().flatten() {
+        (hook.post_wake)(mode, cycles);
+    }
+
+    critical_section::with(|cs| {
+        let idle = IDLE_CYCLES.borrow(cs);
+        idle.set(idle.get() + cycles);
+    });
+    SLEEP_COUNT.fetch_add(1, Ordering::Relaxed);
+
+    unsafe {
+        riscv::interrupt::enable();
+    }
+}
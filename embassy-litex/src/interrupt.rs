@@ -12,13 +12,19 @@ use core::arch::asm;
 use embassy_sync::signal::Signal;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex ;
 
-use crate::{serial::{self, println}, soc_headers};
+use core::cell::Cell;
+use critical_section::Mutex;
+use portable_atomic::{AtomicU32, AtomicUsize, Ordering};
 
-type InterruptCallbackFunctionType = fn(ctx:*mut ())->();
+use crate::{serial::{self, println}, soc_headers, timer};
+#[cfg(feature = "fpu")]
+use crate::fpu;
 
-struct InterruptCallbackType {
-    function: InterruptCallbackFunctionType,
-    context:*mut ()
+pub(crate) type InterruptCallbackFunctionType = fn(ctx:*mut ())->();
+
+pub(crate) struct InterruptCallbackType {
+    pub(crate) function: InterruptCallbackFunctionType,
+    pub(crate) context:*mut ()
 }
 
 pub struct ExternalInterrupt {
@@ -88,6 +94,30 @@ impl ExternalInterrupt {
         }
     }
 
+    /// Capture the cycle counter at trap entry whenever `num` fires.
+    ///
+    /// The capture is read with [`ExternalInterrupt::timestamp`], usually from
+    /// inside the registered callback.
+    pub fn enable_timestamp(num:usize, enable:bool) {
+        if num <= soc_headers::IRQ_NUM_MAX {
+            if enable {
+                IRQ_TIMESTAMP_MASK.fetch_or(0x1 << num, Ordering::Relaxed);
+            } else {
+                IRQ_TIMESTAMP_MASK.fetch_and(!(0x1 << num), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// `sys_clk` cycle count captured at trap entry of the last `num` interrupt.
+    ///
+    /// Returns 0 if timestamps are not enabled for `num` or it never fired.
+    pub fn timestamp(num:usize) -> u64 {
+        if num <= soc_headers::IRQ_NUM_MAX {
+            return critical_section::with(|cs| IRQ_TIMESTAMPS[num].borrow(cs).get());
+        }
+        return 0;
+    }
+
     pub fn un_register_interrupt(num:usize) {
         //serial::print_fmt_func(format_args!("Interrupt de-registering {}\n", num));
         if num <= soc_headers::IRQ_NUM_MAX {
@@ -99,7 +129,111 @@ impl ExternalInterrupt {
 
 }
     
+static IRQ_TIMESTAMP_MASK: AtomicU32 = AtomicU32::new(0);
+static IRQ_TIMESTAMPS: [Mutex<Cell<u64>>; 32] = [const { Mutex::new(Cell::new(0)) }; 32];
+
+
+/// One event of a [`TimestampedInterrupt`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct IrqEvent {
+    /// `sys_clk` cycle count captured at trap entry of this event.
+    pub timestamp: u64,
+    /// Events before this one which were overwritten before a task took them.
+    pub missed: u32,
+}
+
+/// External interrupt with a trap entry timestamp.
+///
+/// Registers itself as the handler of an IRQ and hands every event with its
+/// captured `sys_clk` cycle count to a waiting task. Only the latest event is
+/// kept, older ones not taken in time are counted as missed.
+pub struct TimestampedInterrupt {
+    num: usize,
+    on_event: fn(),
+    signal: Signal<CriticalSectionRawMutex, IrqEvent>,
+    overruns: AtomicU32,
+}
+
+impl TimestampedInterrupt {
+    /// `on_event` acknowledges the event at the source peripheral.
+    pub const fn new(num:usize, on_event: fn()) -> Self {
+        Self { num, on_event, signal: Signal::new(), overruns: AtomicU32::new(0) }
+    }
+
+    /// Register the handler and enable the timestamp capture.
+    ///
+    /// The source peripheral still needs to acknowledge its event, `on_event`
+    /// is called from the interrupt for that before the waiter is signaled.
+    pub fn init(&'static self) {
+        let ptr = self as *const Self as *mut ();
+        ExternalInterrupt::enable_timestamp(self.num, true);
+        ExternalInterrupt::register_interrupt(self.num, external_interrupt_timestamped, ptr);
+    }
+
+    /// Wait for the next event.
+    pub async fn wait_for_irq(&self) -> IrqEvent {
+        self.signal.wait().await
+    }
+
+    /// Returns an event that already happened without waiting.
+    pub fn try_take(&self) -> Option<IrqEvent> {
+        self.signal.try_take()
+    }
+
+    /// Number of events overwritten before a task took them.
+    pub fn overruns(&self) -> u32 {
+        return self.overruns.load(Ordering::Relaxed);
+    }
+}
+
+fn external_interrupt_timestamped(ctx:*mut ()) {
+    let irq = unsafe { &*(ctx as *const TimestampedInterrupt) };
+    (irq.on_event)();
+    let timestamp = ExternalInterrupt::timestamp(irq.num);
+    critical_section::with(|_| {
+        // an event not taken yet is overwritten, carry its count forward
+        let missed = match irq.signal.try_take() {
+            Some(previous) => {
+                irq.overruns.fetch_add(1, Ordering::Relaxed);
+                previous.missed + 1
+            }
+            None => 0,
+        };
+        irq.signal.signal(IrqEvent { timestamp, missed });
+    });
+}
+    
+/// Number of external interrupt, timer and exception handlers in progress.
+static HANDLER_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// True inside an external interrupt, timer alarm or exception handler.
+///
+/// Executor tasks and software interrupt handlers are not counted, they run
+/// at their software interrupt level.
+pub fn in_handler() -> bool {
+    HANDLER_DEPTH.load(Ordering::Relaxed) != 0
+}
+
+/// Run `f` counted as a handler for [`in_handler`].
+///
+/// With the `fpu` feature the FPU is off while `f` runs, handlers don't save
+/// the float registers of the preempted code.
+#[inline(always)]
+pub(crate) fn as_handler<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "fpu")]
+    let fs = fpu::disable();
+    HANDLER_DEPTH.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    HANDLER_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    #[cfg(feature = "fpu")]
+    fpu::enable(fs);
+    return result;
+}
+
 pub fn initialize () {
+    #[cfg(feature = "time-driver-timer0")]
+    timer::init();
+
     unsafe {
         riscv::interrupt::enable();
 
@@ -125,11 +259,58 @@ pub fn initialize () {
     }
 }
 
+/// Run `f` with interrupts enabled from inside a trap handler.
+///
+/// The riscv-rt trap entry only saves the general purpose registers before
+/// calling the handler. A nested trap overwrites `mepc`, `mstatus` and `mcause`,
+/// so the `mret` of the preempted handler would return to the wrong address
+/// and privilege state. The CSRs are kept on the stack of the calling handler
+/// and are restored after interrupts are disabled again.
+///
+/// With the `fpu` feature the float state of the preempted code is kept as well.
+#[inline(always)]
+pub fn nested<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "fpu")]
+    let fpu_context = fpu::save();
+
+    let mepc: usize;
+    let mstatus: usize;
+    let mcause: usize;
+    unsafe {
+        asm!("csrr {0}, mepc", out(reg) mepc);
+        asm!("csrr {0}, mstatus", out(reg) mstatus);
+        asm!("csrr {0}, mcause", out(reg) mcause);
+
+        riscv::interrupt::enable();
+    }
+
+    let result = f();
+
+    riscv::interrupt::disable();
+    unsafe {
+        asm!("csrw mcause, {0}", in(reg) mcause);
+        asm!("csrw mstatus, {0}", in(reg) mstatus);
+        asm!("csrw mepc, {0}", in(reg) mepc);
+    }
+
+    #[cfg(feature = "fpu")]
+    if let Some(context) = &fpu_context {
+        fpu::restore(context);
+    }
+    return result;
+}
+
 #[allow(non_snake_case)]
 #[export_name = "MachineExternalInterruptHandler"]
 pub extern "Rust" fn MachineExternalInterruptHandler(level: u32, interrupt: usize) {
-    use core::ptr;
-    
+    // capture first, everything below adds latency
+    let timestamp_mask = IRQ_TIMESTAMP_MASK.load(Ordering::Relaxed) as usize;
+    let timestamp = if timestamp_mask != 0 { timer::uptime_clk() } else { 0 };
+
+    as_handler(|| external_interrupts(timestamp_mask, timestamp));
+}
+
+fn external_interrupts(timestamp_mask: usize, timestamp: u64) {
     //serial::println("Machine External Interrupt");
 
     const CSR_IRQ_PENDING:usize = 0xFC0;
@@ -141,6 +322,15 @@ pub extern "Rust" fn MachineExternalInterruptHandler(level: u32, interrupt: usiz
         //asm!("csrrc x0, mcause, {mask}", mask = inout(reg) mask);
     }
     //serial::print_fmt_func(format_args!("   Mask: {:08b}\n", mask));
+    if (mask & timestamp_mask) != 0 {
+        critical_section::with(|cs| {
+            for n in 0..32 {
+                if (mask & timestamp_mask & (0x1 << n)) != 0 {
+                    IRQ_TIMESTAMPS[n].borrow(cs).set(timestamp);
+                }
+            }
+        });
+    }
     for n in 0..usize::BITS {
         num = n as usize;
         if ( mask & ( 0x1 << num) ) != 0{
@@ -5,7 +5,30 @@ pub mod interrupt;
 pub mod register;
 pub mod serial;
 pub mod timer;
+pub mod delay;
+pub mod profile;
+pub mod idle;
 pub mod executor;
+pub(crate) mod soft_irq;
+pub mod software_interrupt;
+pub mod ceiling;
+#[cfg(feature = "executor-stats")]
+pub mod stats;
+#[cfg(feature = "task-monitor")]
+pub mod monitor;
+#[cfg(feature = "poll-watchdog")]
+pub mod watchdog;
+#[cfg(feature = "stack-paint")]
+pub mod stack;
+#[cfg(feature = "pmp")]
+pub mod pmp;
+pub mod exception;
+pub mod backtrace;
+#[cfg(feature = "crash-record")]
+pub mod crash;
 pub mod trace;
+pub mod panic;
+#[cfg(feature = "fpu")]
+pub mod fpu;
 
 
@@ -0,0 +1,248 @@
+//! Task monitor.
+//!
+//! With the `task-monitor` feature the embassy-executor trace hooks in
+//! [`trace`](crate::trace) update a fixed-size table of the live tasks of all
+//! executors: their state, poll count, cumulative run time and the time of the
+//! last poll. [`print_top`] prints the table, the [`top`] task does so
+//! periodically together with the CPU share of every task.
+//!
+//! Run times exclude the time a task was preempted by tasks of higher levels.
+//! Tasks are identified by the address of their task header, as reported by
+//! embassy-executor. The `_embassy_trace_task_end` hook removes the entry of a
+//! finished task. Versions of embassy-executor which never call it, like 0.7.0,
+//! leave the entry until its pool slot is spawned again.
+
+use core::cell::{Cell, RefCell};
+
+use critical_section::Mutex;
+use embassy_time::{Duration, Timer};
+
+use crate::executor::{BusyStart, EXECUTOR_LIST, THREAD_EXECUTOR};
+use crate::serial::{self, print_fmt_func};
+use crate::timer;
+
+/// Maximum number of tasks tracked over all executors.
+pub const MAX_TASKS: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaskState {
+    /// Spawned, not yet scheduled.
+    Spawned,
+    /// Woken and waiting in the run queue of its executor.
+    Ready,
+    /// Being polled.
+    Running,
+    /// Returned `Pending`, waiting for a wakeup.
+    Waiting,
+}
+
+impl TaskState {
+    fn as_str(self) -> &'static str {
+        match self {
+            TaskState::Spawned => "spawned",
+            TaskState::Ready => "ready",
+            TaskState::Running => "running",
+            TaskState::Waiting => "waiting",
+        }
+    }
+}
+
+/// Snapshot of one task, times in `sys_clk` cycles.
+#[derive(Clone, Copy)]
+pub struct TaskInfo {
+    /// Trace id of the executor running the task.
+    pub executor_id: usize,
+    pub task_id: u32,
+    pub state: TaskState,
+    pub polls: u32,
+    pub run_cycles: u64,
+    /// `uptime_clk` at the end of the last poll, 0 if never polled.
+    pub last_run: u64,
+    exec_start: BusyStart,
+}
+
+impl TaskInfo {
+    /// Executor level of the task, `None` for the thread-mode executor.
+    pub fn level(&self) -> Option<usize> {
+        EXECUTOR_LIST.iter().position(|executor| executor.id() == self.executor_id)
+    }
+
+    /// True if the task runs on the thread-mode executor.
+    pub fn is_thread_mode(&self) -> bool {
+        return self.executor_id == THREAD_EXECUTOR.id();
+    }
+}
+
+static TASKS: Mutex<RefCell<[Option<TaskInfo>; MAX_TASKS]>> = Mutex::new(RefCell::new([None; MAX_TASKS]));
+
+/// Tasks not tracked because the table was full.
+static DROPPED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+
+fn with_task(executor_id: usize, task_id: u32, f: impl FnOnce(&mut TaskInfo, critical_section::CriticalSection)) {
+    critical_section::with(|cs| {
+        let mut tasks = TASKS.borrow(cs).borrow_mut();
+        if let Some(task) = tasks.iter_mut().flatten().find(|t| t.task_id == task_id && t.executor_id == executor_id) {
+            f(task, cs);
+        }
+    });
+}
+
+pub(crate) fn task_new(executor_id: usize, task_id: u32) {
+    critical_section::with(|cs| {
+        let mut tasks = TASKS.borrow(cs).borrow_mut();
+        // a pool slot spawned again reuses the entry of its previous run
+        let slot = match tasks.iter().position(|t| t.map_or(false, |t| t.task_id == task_id)) {
+            Some(index) => Some(index),
+            None => tasks.iter().position(|t| t.is_none()),
+        };
+        match slot {
+            Some(index) => {
+                tasks[index] = Some(TaskInfo {
+                    executor_id,
+                    task_id,
+                    state: TaskState::Spawned,
+                    polls: 0,
+                    run_cycles: 0,
+                    last_run: 0,
+                    exec_start: BusyStart::NONE,
+                });
+            }
+            None => {
+                let dropped = DROPPED.borrow(cs);
+                dropped.set(dropped.get() + 1);
+            }
+        }
+    });
+}
+
+pub(crate) fn task_end(_executor_id: usize, task_id: u32) {
+    critical_section::with(|cs| {
+        let mut tasks = TASKS.borrow(cs).borrow_mut();
+        if let Some(slot) = tasks.iter_mut().find(|t| t.map_or(false, |t| t.task_id == task_id)) {
+            *slot = None;
+        }
+    });
+}
+
+pub(crate) fn task_ready_begin(executor_id: usize, task_id: u32) {
+    with_task(executor_id, task_id, |task, _| {
+        task.state = TaskState::Ready;
+    });
+}
+
+pub(crate) fn task_exec_begin(executor_id: usize, task_id: u32) {
+    with_task(executor_id, task_id, |task, cs| {
+        task.state = TaskState::Running;
+        task.exec_start = BusyStart::now(cs);
+    });
+}
+
+pub(crate) fn task_exec_end(executor_id: usize, task_id: u32) {
+    with_task(executor_id, task_id, |task, cs| {
+        let busy = task.exec_start.busy_cycles(cs);
+
+        // a task woken while running is already back in the run queue
+        if task.state == TaskState::Running {
+            task.state = TaskState::Waiting;
+        }
+        task.polls = task.polls.wrapping_add(1);
+        task.run_cycles += busy;
+        task.last_run = timer::uptime_clk();
+    });
+}
+
+
+/// Call `f` with a snapshot of every tracked task.
+pub fn for_each(mut f: impl FnMut(&TaskInfo)) {
+    let tasks = snapshot();
+    for task in tasks.iter().flatten() {
+        f(task);
+    }
+}
+
+/// Number of spawned tasks which did not fit into the table.
+pub fn dropped() -> u32 {
+    critical_section::with(|cs| DROPPED.borrow(cs).get())
+}
+
+fn print_executor(task: &TaskInfo) {
+    match task.level() {
+        Some(level) => print_fmt_func(format_args!("{:>5}", level)),
+        None if task.is_thread_mode() => serial::print("   TM"),
+        None => print_fmt_func(format_args!("{:#010x}", task.executor_id)),
+    }
+}
+
+fn snapshot() -> [Option<TaskInfo>; MAX_TASKS] {
+    critical_section::with(|cs| *TASKS.borrow(cs).borrow())
+}
+
+fn print_table(tasks: &[Option<TaskInfo>; MAX_TASKS], now: u64, previous: Option<(&[u64; MAX_TASKS], &[u32; MAX_TASKS], u64)>) {
+    serial::println("Level  Task        State        Polls    Run ms  Last ms ago  CPU%");
+    for (index, task) in tasks.iter().enumerate() {
+        let Some(task) = task else { continue };
+
+        print_executor(task);
+        print_fmt_func(format_args!("  {:#010x}  {:<8} {:>9} {:>9}",
+            task.task_id,
+            task.state.as_str(),
+            task.polls,
+            timer::scale_down(task.run_cycles, timer::CLOCK_HZ, 1_000),
+        ));
+        if task.last_run == 0 {
+            serial::print("            -");
+        } else {
+            print_fmt_func(format_args!("  {:>11}", timer::scale_down(now - task.last_run, timer::CLOCK_HZ, 1_000)));
+        }
+        match previous {
+            Some((run_cycles, ids, window)) if ids[index] == task.task_id => {
+                let busy = task.run_cycles - run_cycles[index].min(task.run_cycles);
+                print_fmt_func(format_args!("  {:>2}.{:01}\n", busy * 100 / window, (busy * 1000 / window) % 10));
+            }
+            _ => serial::print("     -\n"),
+        }
+    }
+
+    let dropped = dropped();
+    if dropped > 0 {
+        print_fmt_func(format_args!("{} tasks not tracked, see MAX_TASKS\n", dropped));
+    }
+}
+
+/// Print all tracked tasks to the console.
+pub fn print_top() {
+    print_table(&snapshot(), timer::uptime_clk(), None);
+}
+
+/// Print all tracked tasks with their CPU share since the last report, every `period`.
+#[embassy_executor::task]
+pub async fn top(period: Duration) {
+    let mut run_cycles = [0u64; MAX_TASKS];
+    let mut ids = [0u32; MAX_TASKS];
+    let mut last_time = timer::uptime_clk();
+
+    loop {
+        Timer::after(period).await;
+
+        let tasks = snapshot();
+        let now = timer::uptime_clk();
+        let window = (now - last_time).max(1);
+        last_time = now;
+
+        print_table(&tasks, now, Some((&run_cycles, &ids, window)));
+
+        for (index, task) in tasks.iter().enumerate() {
+            match task {
+                Some(task) => {
+                    run_cycles[index] = task.run_cycles;
+                    ids[index] = task.task_id;
+                }
+                None => {
+                    run_cycles[index] = 0;
+                    ids[index] = 0;
+                }
+            }
+        }
+    }
+}
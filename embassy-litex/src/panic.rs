@@ -0,0 +1,191 @@
+//! Panic handler.
+//!
+//! With the default `panic-handler` feature this crate defines the
+//! `#[panic_handler]`: it disables interrupts, stores the
+//! [crash record](crate::crash) with `crash-record`, prints the message and a
+//! backtrace, calls the hook set with [`set_hook`] and then
+//!
+//! | feature        | action                                           |
+//! |----------------|--------------------------------------------------|
+//! | none           | halts the CPU                                    |
+//! | `panic-reset`  | resets the SoC through the LiteX ctrl `reset` CSR |
+//! | `panic-ebreak` | executes `ebreak` in a loop for a debugger        |
+//!
+//! Without a debugger attached the `ebreak` traps, the exception handler then
+//! returns to the loop without a report, so the CPU spins like the halt.
+//!
+//! `panic-minimal` prints the panic location, the exception report and the raw
+//! backtrace without `core::fmt`, the crash path then neither formats nor
+//! needs the stack for it. This alone does not remove the formatting code from
+//! the image, the rest of the crate and most applications still use it.
+//!
+//! Applications with their own `#[panic_handler]` disable `panic-handler` and
+//! may still call [`report`], and `reset` with `panic-reset`.
+
+use core::cell::Cell;
+use core::panic::PanicInfo;
+
+use critical_section::Mutex;
+#[cfg(feature = "panic-handler")]
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::backtrace;
+#[cfg(feature = "panic-reset")]
+use crate::register::Register;
+use crate::serial;
+#[cfg(feature = "panic-reset")]
+use crate::soc_headers;
+
+#[cfg(all(feature = "panic-reset", feature = "panic-ebreak"))]
+compile_error!("select at most one of the features panic-reset and panic-ebreak");
+
+/// Called by the panic handler after the report, before the panic action.
+pub type PanicHook = fn(info: &PanicInfo);
+
+static HOOK: Mutex<Cell<Option<PanicHook>>> = Mutex::new(Cell::new(None));
+#[cfg(feature = "panic-handler")]
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Needs the LiteX ctrl core, only referenced with `panic-reset`.
+#[cfg(feature = "panic-reset")]
+const CTRL_RESET_REGISTER: Register = Register { addr: soc_headers::CSR_CTRL_RESET_ADDR };
+/// `soc_rst` field of the ctrl `reset` CSR.
+#[cfg(feature = "panic-reset")]
+const CTRL_RESET_SOC: u32 = 0x1;
+
+
+/// Install `hook`, replacing a previous one.
+pub fn set_hook(hook: PanicHook) {
+    critical_section::with(|cs| HOOK.borrow(cs).set(Some(hook)));
+}
+
+pub fn clear_hook() {
+    critical_section::with(|cs| HOOK.borrow(cs).set(None));
+}
+
+/// Reset the SoC through the LiteX ctrl core, RAM keeps its content.
+#[cfg(feature = "panic-reset")]
+pub fn reset() -> ! {
+    CTRL_RESET_REGISTER.write(CTRL_RESET_SOC);
+    loop {}
+}
+
+/// Print the panic message and the backtrace of the caller.
+#[inline(always)]
+pub fn report(info: &PanicInfo) {
+    serial::println("");
+    serial::println("");
+    serial::println("");
+    serial::println("-------------- Panic ------------");
+    #[cfg(not(feature = "panic-minimal"))]
+    {
+        serial::print_fmt_func(format_args!("{}\r\n", info));
+        backtrace::print();
+    }
+    #[cfg(feature = "panic-minimal")]
+    minimal::report(info, backtrace::frame_pointer());
+}
+
+/// True once the panic handler runs.
+#[cfg(feature = "panic-handler")]
+pub(crate) fn is_panicking() -> bool {
+    return PANICKING.load(Ordering::Relaxed);
+}
+
+/// The panic action selected by the features.
+fn stop() -> ! {
+    #[cfg(feature = "panic-reset")]
+    {
+        serial::println("Resetting CPU.");
+        reset();
+    }
+    #[cfg(feature = "panic-ebreak")]
+    {
+        serial::println("Waiting for debugger.");
+        loop {
+            unsafe { core::arch::asm!("ebreak") };
+        }
+    }
+    #[cfg(not(any(feature = "panic-reset", feature = "panic-ebreak")))]
+    {
+        serial::println("Stopping CPU.");
+        loop {}
+    }
+}
+
+#[cfg(feature = "panic-handler")]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    riscv::interrupt::disable();
+    // a panic in the report or the hook
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        serial::println("Panic in the panic handler.");
+        stop();
+    }
+
+    #[cfg(feature = "crash-record")]
+    crate::crash::record_panic(info, backtrace::frame_pointer());
+
+    report(info);
+    if let Some(hook) = critical_section::with(|cs| HOOK.borrow(cs).get()) {
+        hook(info);
+    }
+    stop();
+}
+
+
+/// Report without `core::fmt`.
+#[cfg(feature = "panic-minimal")]
+pub(crate) mod minimal {
+    use core::panic::PanicInfo;
+
+    use crate::backtrace;
+    use crate::serial;
+
+    /// `value` in decimal, written to the end of `digits`.
+    pub(crate) fn decimal(mut value: u32, digits: &mut [u8; 10]) -> &str {
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        unsafe { core::str::from_utf8_unchecked(&digits[start..]) }
+    }
+
+    pub(crate) fn print_dec(value: u32) {
+        serial::print(decimal(value, &mut [0; 10]));
+    }
+
+    pub(crate) fn print_hex(value: usize) {
+        let mut digits = *b"0x00000000";
+        for i in 0..8 {
+            let nibble = ((value >> (28 - i * 4)) & 0xF) as u8;
+            digits[2 + i] = if nibble < 10 { b'0' + nibble } else { b'a' + nibble - 10 };
+        }
+        serial::print(unsafe { core::str::from_utf8_unchecked(&digits) });
+    }
+
+    pub(super) fn report(info: &PanicInfo, fp: usize) {
+        serial::print("panicked");
+        if let Some(location) = info.location() {
+            serial::print(" at ");
+            serial::print(location.file());
+            serial::print(":");
+            print_dec(location.line());
+        }
+        serial::println("");
+
+        serial::println("Backtrace:");
+        backtrace::walk(fp, |n, pc| {
+            serial::print("#");
+            print_dec(n as u32);
+            serial::print(" pc=");
+            print_hex(pc);
+            serial::println("");
+        });
+    }
+}
@@ -0,0 +1,285 @@
+//! Memory protection with the VexRiscv PMP.
+//!
+//! With the `pmp` feature the thread-mode executor configures the physical
+//! memory protection at boot:
+//!
+//! | entry  | region                              | access    |
+//! |--------|-------------------------------------|-----------|
+//! | 0      | [`GUARD_SIZE`] below `_stack_start` | none      |
+//! | 1-11   | [`add_region`]                      | as given  |
+//! | 12-13  | `.text` and `.rodata`               | read/exec |
+//! | 14     | `.data` up to the end of the heap   | read/write|
+//!
+//! Lower entries take precedence, so the stack guard and application regions
+//! override the large ranges. Every entry is locked, as unlocked entries do
+//! not apply to machine mode, and stays until the next reset. Addresses not
+//! covered by any entry, like the CSRs, keep full access.
+//!
+//! An access violation raises an access fault exception, [`report_fault`]
+//! prints which region was hit.
+
+use core::arch::asm;
+use core::cell::Cell;
+
+use critical_section::Mutex;
+
+use crate::backtrace::stack_bounds;
+use crate::serial::print_fmt_func;
+
+/// Number of PMP entries of the CPU.
+pub const PMP_ENTRIES: usize = 16;
+/// Size of the no-access region below the stack, a power of two.
+pub const GUARD_SIZE: usize = 1024;
+
+const GUARD_ENTRY: usize = 0;
+const FIRST_USER_ENTRY: usize = 1;
+const TEXT_ENTRY: usize = 12;
+const DATA_ENTRY: usize = 14;
+
+const CFG_R: u8 = 0x01;
+const CFG_W: u8 = 0x02;
+const CFG_X: u8 = 0x04;
+const CFG_TOR: u8 = 0x01 << 3;
+const CFG_NAPOT: u8 = 0x03 << 3;
+const CFG_LOCK: u8 = 0x80;
+
+/// Allowed accesses of a region.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Permission {
+    None,
+    Read,
+    ReadWrite,
+    ReadExecute,
+    ReadWriteExecute,
+}
+
+impl Permission {
+    const fn bits(self) -> u8 {
+        match self {
+            Permission::None => 0,
+            Permission::Read => CFG_R,
+            Permission::ReadWrite => CFG_R | CFG_W,
+            Permission::ReadExecute => CFG_R | CFG_X,
+            Permission::ReadWriteExecute => CFG_R | CFG_W | CFG_X,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PmpError {
+    /// No free entries left for the region.
+    NoFreeEntry,
+    /// Start and end are not 4 byte aligned or the region is empty.
+    InvalidRange,
+}
+
+/// A protected address range, `end` exclusive.
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: usize,
+    pub end: usize,
+    pub permission: Permission,
+}
+
+static REGIONS: Mutex<Cell<[Option<Region>; PMP_ENTRIES]>> = Mutex::new(Cell::new([None; PMP_ENTRIES]));
+static NEXT_USER_ENTRY: Mutex<Cell<usize>> = Mutex::new(Cell::new(FIRST_USER_ENTRY));
+
+extern "C" {
+    static __stext: u8;
+    static __erodata: u8;
+    static __euninit: u8;
+    static __eheap: u8;
+}
+
+
+#[inline(always)]
+unsafe fn write_csr<const CSR: usize>(value: usize) {
+    asm!("csrw {csr}, {value}", csr = const CSR, value = in(reg) value);
+}
+
+#[inline(always)]
+unsafe fn read_csr<const CSR: usize>() -> usize {
+    let value: usize;
+    asm!("csrr {value}, {csr}", csr = const CSR, value = out(reg) value);
+    return value;
+}
+
+fn write_pmpaddr(index: usize, value: usize) {
+    unsafe {
+        match index {
+            0 => write_csr::<0x3B0>(value),
+            1 => write_csr::<0x3B1>(value),
+            2 => write_csr::<0x3B2>(value),
+            3 => write_csr::<0x3B3>(value),
+            4 => write_csr::<0x3B4>(value),
+            5 => write_csr::<0x3B5>(value),
+            6 => write_csr::<0x3B6>(value),
+            7 => write_csr::<0x3B7>(value),
+            8 => write_csr::<0x3B8>(value),
+            9 => write_csr::<0x3B9>(value),
+            10 => write_csr::<0x3BA>(value),
+            11 => write_csr::<0x3BB>(value),
+            12 => write_csr::<0x3BC>(value),
+            13 => write_csr::<0x3BD>(value),
+            14 => write_csr::<0x3BE>(value),
+            15 => write_csr::<0x3BF>(value),
+            _ => {}
+        }
+    }
+}
+
+/// Set the config byte of `index`, the other entries of its pmpcfg register are kept.
+fn write_pmpcfg(index: usize, cfg: u8) {
+    let shift = (index % 4) * 8;
+    let mask = !(0xFF << shift);
+    let value = (cfg as usize) << shift;
+    unsafe {
+        match index / 4 {
+            0 => write_csr::<0x3A0>((read_csr::<0x3A0>() & mask) | value),
+            1 => write_csr::<0x3A1>((read_csr::<0x3A1>() & mask) | value),
+            2 => write_csr::<0x3A2>((read_csr::<0x3A2>() & mask) | value),
+            3 => write_csr::<0x3A3>((read_csr::<0x3A3>() & mask) | value),
+            _ => {}
+        }
+    }
+}
+
+/// pmpaddr value of a naturally aligned power of two region.
+const fn napot_addr(start: usize, size: usize) -> usize {
+    (start >> 2) | ((size >> 3) - 1)
+}
+
+const fn is_napot(start: usize, size: usize) -> bool {
+    size >= 8 && size.is_power_of_two() && start % size == 0
+}
+
+fn record(index: usize, region: Region) {
+    critical_section::with(|cs| {
+        let cell = REGIONS.borrow(cs);
+        let mut regions = cell.get();
+        regions[index] = Some(region);
+        cell.set(regions);
+    });
+}
+
+/// Program `region` into entry `index`, or `index` and `index + 1` if it is
+/// not a naturally aligned power of two. Returns the number of entries used.
+fn program(index: usize, region: Region) -> usize {
+    let size = region.end - region.start;
+    let perm = region.permission.bits();
+    if is_napot(region.start, size) {
+        write_pmpaddr(index, napot_addr(region.start, size));
+        write_pmpcfg(index, perm | CFG_NAPOT | CFG_LOCK);
+        record(index, region);
+        return 1;
+    }
+    // TOR matches pmpaddr[index] <= addr < pmpaddr[index + 1]
+    write_pmpaddr(index, region.start >> 2);
+    write_pmpaddr(index + 1, region.end >> 2);
+    write_pmpcfg(index, CFG_LOCK);
+    write_pmpcfg(index + 1, perm | CFG_TOR | CFG_LOCK);
+    record(index + 1, region);
+    return 2;
+}
+
+/// Configure and lock the stack guard, code and data entries.
+///
+/// Called once by the thread-mode executor at boot.
+pub fn init() {
+    let (stack_low, _) = stack_bounds();
+    let text_start = core::ptr::addr_of!(__stext) as usize;
+    let rodata_end = core::ptr::addr_of!(__erodata) as usize;
+    let heap_end = core::ptr::addr_of!(__eheap) as usize;
+    let bss_end = core::ptr::addr_of!(__euninit) as usize;
+
+    if bss_end > stack_low - GUARD_SIZE {
+        panic!("PMP: .bss/.uninit ends at {:#010x}, inside the stack guard below {:#010x}", bss_end, stack_low);
+    }
+
+    program(GUARD_ENTRY, Region {
+        name: "stack guard",
+        start: stack_low - GUARD_SIZE,
+        end: stack_low,
+        permission: Permission::None,
+    });
+
+    // always TOR, the data entry starts at the end address of this one
+    write_pmpaddr(TEXT_ENTRY, text_start >> 2);
+    write_pmpaddr(TEXT_ENTRY + 1, rodata_end >> 2);
+    write_pmpcfg(TEXT_ENTRY, CFG_LOCK);
+    write_pmpcfg(TEXT_ENTRY + 1, Permission::ReadExecute.bits() | CFG_TOR | CFG_LOCK);
+    record(TEXT_ENTRY + 1, Region {
+        name: ".text/.rodata",
+        start: text_start,
+        end: rodata_end,
+        permission: Permission::ReadExecute,
+    });
+
+    write_pmpaddr(DATA_ENTRY, heap_end >> 2);
+    write_pmpcfg(DATA_ENTRY, Permission::ReadWrite.bits() | CFG_TOR | CFG_LOCK);
+    record(DATA_ENTRY, Region {
+        name: "data/stack/heap",
+        start: rodata_end,
+        end: heap_end,
+        permission: Permission::ReadWrite,
+    });
+}
+
+/// Protect `start..end` with `permission` until the next reset.
+///
+/// The region overrides the built-in code and data entries. Naturally aligned
+/// power of two regions use one entry, others two. Returns the first entry used.
+pub fn add_region(name: &'static str, start: usize, end: usize, permission: Permission) -> Result<usize, PmpError> {
+    if start % 4 != 0 || end % 4 != 0 || end <= start {
+        return Err(PmpError::InvalidRange);
+    }
+    let needed = if is_napot(start, end - start) { 1 } else { 2 };
+
+    critical_section::with(|cs| {
+        let next = NEXT_USER_ENTRY.borrow(cs);
+        let index = next.get();
+        if index + needed > TEXT_ENTRY {
+            return Err(PmpError::NoFreeEntry);
+        }
+        next.set(index + program(index, Region { name, start, end, permission }));
+        return Ok(index);
+    })
+}
+
+/// Call `f` for every configured region, in order of precedence.
+pub fn for_each(mut f: impl FnMut(&Region)) {
+    let regions = critical_section::with(|cs| REGIONS.borrow(cs).get());
+    for region in regions.iter().flatten() {
+        f(region);
+    }
+}
+
+/// Region with the highest precedence containing `addr`.
+pub fn find_region(addr: usize) -> Option<Region> {
+    let regions = critical_section::with(|cs| REGIONS.borrow(cs).get());
+    regions.iter().flatten().find(|r| (r.start..r.end).contains(&addr)).copied()
+}
+
+/// Print a report for an access fault, returns false if `mcause` is no access fault.
+pub fn report_fault(mcause: usize, mepc: usize, mtval: usize) -> bool {
+    let access = match mcause {
+        1 => "execute",
+        5 => "load",
+        7 => "store",
+        _ => return false,
+    };
+
+    print_fmt_func(format_args!("\r\n-------------- Access fault ------------\r\n{} at {:#010x}, pc {:#010x}\r\n", access, mtval, mepc));
+    match find_region(mtval) {
+        Some(region) => {
+            print_fmt_func(format_args!("Region '{}' {:#010x}..{:#010x} allows {:?}\r\n",
+                region.name, region.start, region.end, region.permission));
+            if region.permission == Permission::None && region.end == stack_bounds().0 {
+                print_fmt_func(format_args!("Stack overflow, the stack grew below {:#010x}\r\n", region.end));
+            }
+        }
+        None => print_fmt_func(format_args!("No PMP region, bus error or unmapped address\r\n")),
+    }
+    return true;
+}
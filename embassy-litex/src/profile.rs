@@ -0,0 +1,220 @@
+//! Cycle-precise code profiling.
+//!
+//! [`Stopwatch`] measures a single section, [`Span`] collects min/max/avg
+//! statistics for a named section over many runs. Spans register themselves on
+//! their first measurement and [`dump`] prints all of them.
+//!
+//! ```ignore
+//! use embassy_litex::profile_span;
+//!
+//! profile_span!("filter", {
+//!     run_filter(&mut samples);
+//! });
+//! embassy_litex::profile::dump();
+//! ```
+
+use core::cell::{Cell, RefCell};
+
+use critical_section::Mutex;
+use embassy_time::Duration;
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::serial::print_fmt_func;
+use crate::timer;
+
+/// Maximum number of spans listed by [`dump`].
+pub const MAX_SPANS: usize = 32;
+
+/// Convert `sys_clk` cycles to an embassy-time duration.
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    Duration::from_ticks(timer::cycles_to_ticks(cycles))
+}
+
+
+/// Measures the cycles since it was started.
+#[derive(Clone, Copy)]
+pub struct Stopwatch {
+    start: u64,
+}
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Self { start: timer::uptime_clk() }
+    }
+
+    /// Restart and return the cycles measured until now.
+    pub fn restart(&mut self) -> u64 {
+        let now = timer::uptime_clk();
+        let elapsed = now - self.start;
+        self.start = now;
+        return elapsed;
+    }
+
+    pub fn elapsed_cycles(&self) -> u64 {
+        return timer::uptime_clk() - self.start;
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        return cycles_to_duration(self.elapsed_cycles());
+    }
+}
+
+
+/// Statistics of a [`Span`] in `sys_clk` cycles.
+#[derive(Clone, Copy)]
+pub struct SpanStats {
+    pub count: u64,
+    pub total: u64,
+    pub min: u64,
+    pub max: u64,
+}
+
+impl SpanStats {
+    const fn new() -> Self {
+        Self { count: 0, total: 0, min: u64::MAX, max: 0 }
+    }
+
+    pub fn avg(&self) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        return self.total / self.count;
+    }
+
+    pub fn min_duration(&self) -> Duration {
+        cycles_to_duration(if self.count == 0 { 0 } else { self.min })
+    }
+
+    pub fn max_duration(&self) -> Duration {
+        cycles_to_duration(self.max)
+    }
+
+    pub fn avg_duration(&self) -> Duration {
+        cycles_to_duration(self.avg())
+    }
+}
+
+/// Named code section with accumulated timing statistics.
+///
+/// Meant to be placed in a `static`, usually through [`profile_span!`](crate::profile_span).
+pub struct Span {
+    name: &'static str,
+    stats: Mutex<Cell<SpanStats>>,
+    registered: AtomicBool,
+}
+
+impl Span {
+    pub const fn new(name: &'static str) -> Self {
+        Self { name, stats: Mutex::new(Cell::new(SpanStats::new())), registered: AtomicBool::new(false) }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Start a measurement, it is recorded when the guard is dropped.
+    #[inline(always)]
+    pub fn enter(&'static self) -> SpanGuard {
+        SpanGuard { span: self, start: timer::uptime_clk() }
+    }
+
+    /// Add a measurement of `cycles`.
+    pub fn record(&'static self, cycles: u64) {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            register(self);
+        }
+        critical_section::with(|cs| {
+            let cell = self.stats.borrow(cs);
+            let mut stats = cell.get();
+            stats.count += 1;
+            stats.total += cycles;
+            stats.min = stats.min.min(cycles);
+            stats.max = stats.max.max(cycles);
+            cell.set(stats);
+        });
+    }
+
+    pub fn stats(&self) -> SpanStats {
+        critical_section::with(|cs| self.stats.borrow(cs).get())
+    }
+
+    pub fn reset(&self) {
+        critical_section::with(|cs| self.stats.borrow(cs).set(SpanStats::new()));
+    }
+}
+
+/// Running measurement of a [`Span`].
+pub struct SpanGuard {
+    span: &'static Span,
+    start: u64,
+}
+
+impl Drop for SpanGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        let cycles = timer::uptime_clk() - self.start;
+        self.span.record(cycles);
+    }
+}
+
+
+static SPANS: Mutex<RefCell<[Option<&'static Span>; MAX_SPANS]>> = Mutex::new(RefCell::new([None; MAX_SPANS]));
+
+/// Spans not listed because [`MAX_SPANS`] were registered already.
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+fn register(span: &'static Span) {
+    critical_section::with(|cs| {
+        let mut spans = SPANS.borrow(cs).borrow_mut();
+        match spans.iter_mut().find(|s| s.is_none()) {
+            Some(slot) => *slot = Some(span),
+            None => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+/// Number of spans missing in [`for_each`] and [`dump`], their statistics are
+/// still kept in the span itself.
+pub fn dropped() -> u32 {
+    return DROPPED.load(Ordering::Relaxed);
+}
+
+/// Call `f` for every span that recorded at least one measurement.
+pub fn for_each(mut f: impl FnMut(&'static Span)) {
+    let spans = critical_section::with(|cs| *SPANS.borrow(cs).borrow());
+    for span in spans.iter().flatten() {
+        f(span);
+    }
+}
+
+/// Print the statistics of all spans to the console.
+pub fn dump() {
+    print_fmt_func(format_args!("{:<24} {:>10} {:>12} {:>12} {:>12} {:>10}\n", "span", "count", "min", "avg", "max", "avg us"));
+    for_each(|span| {
+        let stats = span.stats();
+        print_fmt_func(format_args!("{:<24} {:>10} {:>12} {:>12} {:>12} {:>10}\n",
+            span.name(),
+            stats.count,
+            if stats.count == 0 { 0 } else { stats.min },
+            stats.avg(),
+            stats.max,
+            stats.avg_duration().as_micros(),
+        ));
+    });
+    let dropped = dropped();
+    if dropped != 0 {
+        print_fmt_func(format_args!("{} spans not listed, more than {} registered\n", dropped, MAX_SPANS));
+    }
+}
+
+/// Measure a block as a named [`Span`](crate::profile::Span), evaluates to the block's value.
+#[macro_export]
+macro_rules! profile_span {
+    ($name:expr, $body:block) => {{
+        static __SPAN: $crate::profile::Span = $crate::profile::Span::new($name);
+        let __guard = __SPAN.enter();
+        $body
+    }};
+}
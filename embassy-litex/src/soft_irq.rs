@@ -0,0 +1,247 @@
+//! Backend of the software interrupt levels.
+//!
+//! The executor levels and [`SoftwareInterrupt`](crate::software_interrupt::SoftwareInterrupt)
+//! are driven by one request bit and one active bit per level. The machine
+//! software interrupt is raised while the highest requested level is above
+//! the highest active one, so a running level is only preempted by higher
+//! levels.
+//!
+//! By default the bits live in the `software_interrupt` CSRs of the patched
+//! LiteX VexRiscv, see `sim.py`. With the `soft-irq-msip` feature they are
+//! kept in RAM instead and the single standard machine software interrupt
+//! (`msip` of the CLINT) is raised and cleared in software, which works on
+//! unmodified LiteX SoCs with a CLINT.
+//!
+//! Besides the running levels a level can be raised as a priority ceiling,
+//! see [`ceiling`](crate::ceiling). Both hold back requests the same way but
+//! are kept apart, so [`running_level`] is the level of the running executor
+//! or software interrupt and never a ceiling.
+
+#[cfg(not(feature = "soft-irq-msip"))]
+pub(crate) use self::csr::*;
+#[cfg(feature = "soft-irq-msip")]
+pub(crate) use self::msip::*;
+
+/// Raise `level` as a ceiling if it is neither running nor raised yet.
+///
+/// Returns true if this call raised it and [`lower_ceiling`] has to undo it.
+pub(crate) fn raise(level: usize) -> bool {
+    critical_section::with(|_| {
+        if (active() | raised()) & (0x1 << level) != 0 {
+            return false;
+        }
+        raise_ceiling(level);
+        return true;
+    })
+}
+
+/// Level of the running executor or software interrupt, `None` in thread mode.
+pub(crate) fn running_level() -> Option<usize> {
+    let active = active();
+    if active == 0 {
+        return None;
+    }
+    return Some(31 - active.leading_zeros() as usize);
+}
+
+
+#[cfg(not(feature = "soft-irq-msip"))]
+mod csr {
+    use portable_atomic::{AtomicU32, Ordering};
+
+    use crate::executor::MAX_LEVELS;
+    use crate::register::Register;
+    use crate::soc_headers;
+
+    pub(crate) const SOFTWARE_INTERRUPT_REQUEST_REGISTER:Register = Register { addr: soc_headers::CSR_CPU_SOFTWARE_INTERRUPT_REQUEST_INTERRUPT_ADDR };
+    pub(crate) const SOFTWARE_INTERRUPT_ACTIVE_REGISTER:Register = Register { addr: soc_headers::CSR_CPU_SOFTWARE_INTERRUPT_ACTIVE_INTERRUPT_ADDR };
+    pub(crate) const SOFTWARE_INTERRUPT_STATUS_REGISTER:Register = Register { addr: soc_headers::CSR_CPU_SOFTWARE_INTERRUPT_STATUS_INTERRUPT_ADDR };
+
+    pub(crate) const CSR_OPERATION_SET:u32 = 0x8000_0000;
+    pub(crate) const CSR_OPERATION_CLEAR:u32 = 0x4000_0000;
+    pub(crate) const CSR_OPERATION_REPLACE:u32 = 0x0000_0000;
+
+    /// Running levels, the CSR holds these and the raised ceilings.
+    static ACTIVE: AtomicU32 = AtomicU32::new(0);
+    /// Levels raised as ceilings.
+    static RAISED: AtomicU32 = AtomicU32::new(0);
+
+    /// Request `level`, it runs as soon as no higher level is active.
+    #[inline(always)]
+    pub(crate) fn request(level: usize) {
+        SOFTWARE_INTERRUPT_REQUEST_REGISTER.write(CSR_OPERATION_SET + (0x1 << level));
+    }
+
+    /// Take the highest requested level, called from the software interrupt handler.
+    pub(crate) fn take_highest() -> Option<usize> {
+        critical_section::with(|_| {
+            // status holds the number of the highest requested level
+            let num = SOFTWARE_INTERRUPT_STATUS_REGISTER.read() as usize;
+            if num < MAX_LEVELS {
+                SOFTWARE_INTERRUPT_REQUEST_REGISTER.write(CSR_OPERATION_CLEAR + (0x1 << num));
+                SOFTWARE_INTERRUPT_REQUEST_REGISTER.write(CSR_OPERATION_CLEAR + (0x1 << num));
+                return Some(num);
+            }
+            return None;
+        })
+    }
+
+    #[inline(always)]
+    fn set_csr(level: usize) {
+        SOFTWARE_INTERRUPT_ACTIVE_REGISTER.write(CSR_OPERATION_SET + (0x1 << level));
+        // read back, the interrupt line is updated once the write has reached the CSR
+        SOFTWARE_INTERRUPT_ACTIVE_REGISTER.read();
+    }
+
+    /// Clear the CSR bit of `level` unless `other` still holds it.
+    #[inline(always)]
+    fn clear_csr(level: usize, other: &AtomicU32) {
+        critical_section::with(|_| {
+            if other.load(Ordering::Relaxed) & (0x1 << level) == 0 {
+                SOFTWARE_INTERRUPT_ACTIVE_REGISTER.write(CSR_OPERATION_CLEAR + (0x1 << level));
+            }
+        });
+    }
+
+    /// Mark `level` as running, requests of this and lower levels are held back.
+    #[inline(always)]
+    pub(crate) fn set_active(level: usize) {
+        ACTIVE.fetch_or(0x1 << level, Ordering::Relaxed);
+        set_csr(level);
+    }
+
+    #[inline(always)]
+    pub(crate) fn clear_active(level: usize) {
+        ACTIVE.fetch_and(!(0x1 << level), Ordering::Relaxed);
+        clear_csr(level, &RAISED);
+    }
+
+    /// Hold back requests of `level` and below without running it.
+    pub(crate) fn raise_ceiling(level: usize) {
+        RAISED.fetch_or(0x1 << level, Ordering::Relaxed);
+        set_csr(level);
+    }
+
+    pub(crate) fn lower_ceiling(level: usize) {
+        RAISED.fetch_and(!(0x1 << level), Ordering::Relaxed);
+        clear_csr(level, &ACTIVE);
+    }
+
+    #[inline(always)]
+    pub(crate) fn active() -> u32 {
+        ACTIVE.load(Ordering::Relaxed)
+    }
+
+    #[inline(always)]
+    pub(crate) fn raised() -> u32 {
+        RAISED.load(Ordering::Relaxed)
+    }
+}
+
+
+#[cfg(feature = "soft-irq-msip")]
+mod msip {
+    use portable_atomic::{AtomicU32, Ordering};
+
+    use crate::executor::MAX_LEVELS;
+    use crate::register::Register;
+    use crate::soc_headers;
+
+    /// `msip` of hart 0 at the start of the CLINT.
+    const MSIP_REGISTER:Register = Register { addr: soc_headers::MEM_CLINT_BASE_ADDR };
+
+    /// Requested levels, one bit per level.
+    static PENDING: AtomicU32 = AtomicU32::new(0);
+    /// Running levels. Levels only preempt lower ones, so the set bits form a
+    /// stack with the running level on top.
+    static ACTIVE: AtomicU32 = AtomicU32::new(0);
+    /// Levels raised as ceilings.
+    static RAISED: AtomicU32 = AtomicU32::new(0);
+
+    /// Bit of the highest set bit of `bits`, 0 if none.
+    #[inline(always)]
+    fn highest_bit(bits: u32) -> u32 {
+        if bits == 0 {
+            return 0;
+        }
+        return 1 << (31 - bits.leading_zeros());
+    }
+
+    /// Raise `msip` while a requested level is above all active and raised levels.
+    /// Must be called inside a critical section after changing the bitmaps.
+    fn update_msip() {
+        let pending = highest_bit(PENDING.load(Ordering::Relaxed));
+        let active = highest_bit(ACTIVE.load(Ordering::Relaxed) | RAISED.load(Ordering::Relaxed));
+        MSIP_REGISTER.write((pending > active) as u32);
+    }
+
+    /// Request `level`, it runs as soon as no higher level is active.
+    pub(crate) fn request(level: usize) {
+        critical_section::with(|_| {
+            PENDING.fetch_or(0x1 << level, Ordering::Relaxed);
+            update_msip();
+        });
+    }
+
+    /// Take the highest requested level, called from the software interrupt handler.
+    pub(crate) fn take_highest() -> Option<usize> {
+        critical_section::with(|_| {
+            let pending = PENDING.load(Ordering::Relaxed);
+            let highest = highest_bit(pending);
+            if highest == 0 || highest <= highest_bit(ACTIVE.load(Ordering::Relaxed) | RAISED.load(Ordering::Relaxed)) {
+                // spurious, e.g. msip raised before the level got active
+                update_msip();
+                return None;
+            }
+            let num = highest.trailing_zeros() as usize;
+            PENDING.store(pending & !highest, Ordering::Relaxed);
+            update_msip();
+            if num < MAX_LEVELS {
+                return Some(num);
+            }
+            return None;
+        })
+    }
+
+    /// Mark `level` as running, requests of this and lower levels are held back.
+    pub(crate) fn set_active(level: usize) {
+        critical_section::with(|_| {
+            ACTIVE.fetch_or(0x1 << level, Ordering::Relaxed);
+            update_msip();
+        });
+    }
+
+    /// Pop `level`, a held back lower request raises `msip` again.
+    pub(crate) fn clear_active(level: usize) {
+        critical_section::with(|_| {
+            ACTIVE.fetch_and(!(0x1 << level), Ordering::Relaxed);
+            update_msip();
+        });
+    }
+
+    /// Hold back requests of `level` and below without running it.
+    pub(crate) fn raise_ceiling(level: usize) {
+        critical_section::with(|_| {
+            RAISED.fetch_or(0x1 << level, Ordering::Relaxed);
+            update_msip();
+        });
+    }
+
+    /// Lower the ceiling `level`, a held back request raises `msip` again.
+    pub(crate) fn lower_ceiling(level: usize) {
+        critical_section::with(|_| {
+            RAISED.fetch_and(!(0x1 << level), Ordering::Relaxed);
+            update_msip();
+        });
+    }
+
+    #[inline(always)]
+    pub(crate) fn active() -> u32 {
+        ACTIVE.load(Ordering::Relaxed)
+    }
+
+    #[inline(always)]
+    pub(crate) fn raised() -> u32 {
+        RAISED.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,94 @@
+//! User-defined software interrupts.
+//!
+//! The software interrupt CSRs, or their RAM emulation with the
+//! `soft-irq-msip` feature, provide one request bit per priority level. By
+//! default every level drives one of the [`EXECUTOR_LIST`] executors, but a
+//! level can also be reserved for a plain handler function which is run
+//! whenever the level is pended. Handlers run at the priority of their level
+//! with interrupts enabled, so higher levels (executors or other software
+//! interrupts) can still preempt them.
+//!
+//! A level is either used by an executor or by a software interrupt, never both.
+
+use crate::executor::{EXECUTOR_LIST, MAX_LEVELS};
+use crate::interrupt::{self, InterruptCallbackFunctionType, InterruptCallbackType};
+use crate::serial;
+use crate::soft_irq;
+
+pub struct SoftwareInterrupt {
+    functions: [InterruptCallbackType; MAX_LEVELS],
+    reserved: [bool; MAX_LEVELS],
+}
+
+const SOFTWARE_INTERRUPT_DEFAULT: InterruptCallbackType =
+    InterruptCallbackType { function: software_interrupt_default, context: 0 as *mut () };
+
+static mut SOFTWARE_INTERRUPT: SoftwareInterrupt = SoftwareInterrupt {
+    functions: [SOFTWARE_INTERRUPT_DEFAULT; MAX_LEVELS],
+    reserved: [false; MAX_LEVELS],
+};
+
+
+impl SoftwareInterrupt {
+    /// Reserve `level` for a software interrupt and install its handler.
+    ///
+    /// Returns false if the level is out of range or an executor was already
+    /// started on it.
+    pub fn register_interrupt(level: usize, function: InterruptCallbackFunctionType, context: *mut ()) -> bool {
+        if level >= MAX_LEVELS {
+            return false;
+        }
+
+        critical_section::with(|_| {
+            if EXECUTOR_LIST[level].is_started() {
+                serial::print_fmt_func(format_args!("Software interrupt {} already used by executor\n", level));
+                return false;
+            }
+            unsafe {
+                SOFTWARE_INTERRUPT.functions[level] = InterruptCallbackType { function, context };
+                SOFTWARE_INTERRUPT.reserved[level] = true;
+            }
+            return true;
+        })
+    }
+
+    /// Release `level`, it can be used by an executor again afterwards.
+    pub fn un_register_interrupt(level: usize) {
+        if level < MAX_LEVELS {
+            critical_section::with(|_| unsafe {
+                SOFTWARE_INTERRUPT.functions[level] = SOFTWARE_INTERRUPT_DEFAULT;
+                SOFTWARE_INTERRUPT.reserved[level] = false;
+            });
+        }
+    }
+
+    /// Request the handler of `level` to run.
+    ///
+    /// Can be called from any context, the handler runs as soon as no higher
+    /// level is active.
+    pub fn pend(level: usize) {
+        if is_reserved(level) {
+            soft_irq::request(level);
+        }
+    }
+
+    pub(crate) fn execute_interrupt(level: usize) {
+        let (function, context) = unsafe {
+            (SOFTWARE_INTERRUPT.functions[level].function, SOFTWARE_INTERRUPT.functions[level].context)
+        };
+
+        // allow higher levels to preempt the handler, same as the executors
+        interrupt::nested(|| function(context));
+    }
+}
+
+/// Returns true if `level` is reserved for a software interrupt.
+pub fn is_reserved(level: usize) -> bool {
+    if level >= MAX_LEVELS {
+        return false;
+    }
+    unsafe { SOFTWARE_INTERRUPT.reserved[level] }
+}
+
+pub fn software_interrupt_default(_: *mut ()) {
+}
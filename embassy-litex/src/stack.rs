@@ -0,0 +1,206 @@
+//! Stack painting and high-water mark.
+//!
+//! All executor levels and nested traps share the single stack between
+//! `_stack_start` and `__sstack` from `link.x`, growing down from its upper
+//! end. With the `stack-paint` feature the thread-mode executor fills the
+//! unused part with [`PAINT_PATTERN`] at boot, [`high_water_mark`] then
+//! returns the deepest use so far.
+//!
+//! The search for the deepest use stops at [`FREE_RUN_WORDS`] consecutive
+//! painted words, a frame which leaves a larger gap unwritten below a deeper
+//! write is not seen until that write is reached otherwise.
+//!
+//! With `stack-sampling` the high-water mark is also updated when an executor
+//! level starts and finishes a poll. A new depth found on entry is charged to
+//! the preempted level, on exit to the level itself, [`level_peak`] returns
+//! the deepest use charged to a level.
+//!
+//! Reaching the lower end of the stack means the memory below it has been
+//! overwritten, [`check`] panics with a stack overflow message in that case.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use portable_atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[cfg(feature = "stack-sampling")]
+use crate::executor::{Priority, MAX_LEVELS};
+use crate::backtrace;
+use crate::serial::print_fmt_func;
+#[cfg(feature = "stack-sampling")]
+use crate::soft_irq;
+
+/// Value of unused stack words.
+pub const PAINT_PATTERN: u32 = 0xA5A5_A5A5;
+/// Number of consecutive painted words taken as the end of the used stack.
+pub const FREE_RUN_WORDS: usize = 16;
+/// Bytes below the stack pointer of [`paint`] which are left as they are.
+const PAINT_MARGIN: usize = 256;
+
+static PAINTED: AtomicBool = AtomicBool::new(false);
+/// Lowest stack address known to be used.
+static LOWEST: Mutex<Cell<usize>> = Mutex::new(Cell::new(usize::MAX));
+
+
+/// Lower and upper end of the stack.
+pub fn bounds() -> (usize, usize) {
+    backtrace::stack_bounds()
+}
+
+/// Size of the stack in bytes.
+pub fn size() -> usize {
+    let (low, high) = bounds();
+    return high - low;
+}
+
+#[inline(always)]
+fn stack_pointer() -> usize {
+    let sp: usize;
+    unsafe {
+        core::arch::asm!("mv {0}, sp", out(reg) sp);
+    }
+    return sp;
+}
+
+/// Bytes in use at the current stack pointer.
+#[inline(always)]
+pub fn current_depth() -> usize {
+    let (_, high) = bounds();
+    return high.saturating_sub(stack_pointer());
+}
+
+/// Fill the unused stack with [`PAINT_PATTERN`].
+///
+/// Called once by the thread-mode executor before interrupts are enabled.
+pub fn paint() {
+    let (low, _) = bounds();
+    let end = (stack_pointer() - PAINT_MARGIN) & !0x3;
+
+    let mut addr = low;
+    while addr < end {
+        unsafe {
+            (addr as *mut u32).write_volatile(PAINT_PATTERN);
+        }
+        addr += 4;
+    }
+
+    critical_section::with(|cs| LOWEST.borrow(cs).set(end));
+    PAINTED.store(true, Ordering::Release);
+}
+
+/// Move the lowest used address down to the deepest written word, returns true if it moved.
+fn scan(cs: critical_section::CriticalSection) -> bool {
+    let (low, _) = bounds();
+    let lowest = LOWEST.borrow(cs);
+    let mut new_lowest = lowest.get();
+    let mut addr = new_lowest;
+    let mut free = 0;
+
+    while addr > low && free < FREE_RUN_WORDS {
+        addr -= 4;
+        if unsafe { (addr as *const u32).read_volatile() } == PAINT_PATTERN {
+            free += 1;
+        } else {
+            new_lowest = addr;
+            free = 0;
+        }
+    }
+
+    if new_lowest < lowest.get() {
+        lowest.set(new_lowest);
+        return true;
+    }
+    return false;
+}
+
+/// Deepest stack use in bytes since [`paint`], 0 if the stack is not painted.
+pub fn high_water_mark() -> usize {
+    if !PAINTED.load(Ordering::Acquire) {
+        return 0;
+    }
+    let (_, high) = bounds();
+    critical_section::with(|cs| {
+        scan(cs);
+        high - LOWEST.borrow(cs).get()
+    })
+}
+
+/// Panic if the stack has grown down to its lower end.
+pub fn check() {
+    if !PAINTED.load(Ordering::Acquire) {
+        return;
+    }
+    let (low, high) = bounds();
+    let bottom = unsafe { (low as *const u32).read_volatile() };
+    if bottom != PAINT_PATTERN || critical_section::with(|cs| LOWEST.borrow(cs).get()) <= low {
+        panic!("Stack overflow: the stack of {} bytes reached _stack_start {:#010x}, the memory below is corrupted",
+            high - low, low);
+    }
+}
+
+/// Print the stack size and high-water mark, and the peak of every level with `stack-sampling`.
+pub fn report() {
+    let used = high_water_mark();
+    let size = size();
+    print_fmt_func(format_args!("Stack: {} of {} bytes used ({}%)\n", used, size, used * 100 / size.max(1)));
+
+    #[cfg(feature = "stack-sampling")]
+    critical_section::with(|cs| {
+        let peaks = LEVEL_PEAK.borrow(cs).get();
+        for level in 0..MAX_LEVELS {
+            if peaks[level] != 0 {
+                print_fmt_func(format_args!("  level {:>2}: {} bytes\n", level, peaks[level]));
+            }
+        }
+        print_fmt_func(format_args!("  thread  : {} bytes\n", peaks[MAX_LEVELS]));
+    });
+}
+
+
+/// Deepest use charged to every level, thread mode at index `MAX_LEVELS`.
+#[cfg(feature = "stack-sampling")]
+static LEVEL_PEAK: Mutex<Cell<[usize; MAX_LEVELS + 1]>> = Mutex::new(Cell::new([0; MAX_LEVELS + 1]));
+
+#[cfg(feature = "stack-sampling")]
+fn sample(slot: usize) {
+    if !PAINTED.load(Ordering::Acquire) {
+        return;
+    }
+    let (_, high) = bounds();
+    critical_section::with(|cs| {
+        if scan(cs) {
+            let cell = LEVEL_PEAK.borrow(cs);
+            let mut peaks = cell.get();
+            peaks[slot] = peaks[slot].max(high - LOWEST.borrow(cs).get());
+            cell.set(peaks);
+        }
+    });
+}
+
+/// Called by `on_interrupt` before polling `level`.
+#[cfg(feature = "stack-sampling")]
+pub(crate) fn level_enter(level: usize) {
+    // the preempted level is the highest active one below `level`
+    let below = soft_irq::active() & ((0x1 << level) - 1);
+    let preempted = if below == 0 { MAX_LEVELS } else { 31 - below.leading_zeros() as usize };
+    sample(preempted);
+}
+
+/// Called by `on_interrupt` after polling `level`.
+#[cfg(feature = "stack-sampling")]
+pub(crate) fn level_exit(level: usize) {
+    sample(level);
+    check();
+}
+
+/// Deepest stack use charged to the executor at `priority`.
+#[cfg(feature = "stack-sampling")]
+pub fn level_peak(priority: Priority) -> usize {
+    critical_section::with(|cs| LEVEL_PEAK.borrow(cs).get()[priority.as_level()])
+}
+
+/// Deepest stack use charged to the thread-mode executor.
+#[cfg(feature = "stack-sampling")]
+pub fn thread_mode_peak() -> usize {
+    critical_section::with(|cs| LEVEL_PEAK.borrow(cs).get()[MAX_LEVELS])
+}
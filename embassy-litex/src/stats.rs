@@ -0,0 +1,149 @@
+//! Scheduling statistics of the interrupt executors.
+//!
+//! With the `executor-stats` feature every [`InterruptExecutor`] counts its
+//! polls, the cycles spent polling, the longest poll and the latency from the
+//! first `__pender` call to the start of the poll. Busy cycles exclude the time
+//! a level was preempted by higher levels, so the utilization of all levels
+//! adds up to at most 100%.
+//!
+//! [`InterruptExecutor`]: crate::executor::InterruptExecutor
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embassy_time::{Duration, Timer};
+
+use crate::executor::{Priority, EXECUTOR_LIST, MAX_LEVELS};
+use crate::serial::{self, print_fmt_func};
+use crate::timer;
+
+/// Snapshot of the statistics of one executor level, times in `sys_clk` cycles.
+#[derive(Clone, Copy, Default)]
+pub struct ExecutorStats {
+    pub polls: u32,
+    pub busy_cycles: u64,
+    pub max_poll_cycles: u64,
+    pub latency_total_cycles: u64,
+    pub max_latency_cycles: u64,
+}
+
+impl ExecutorStats {
+    const fn new() -> Self {
+        Self { polls: 0, busy_cycles: 0, max_poll_cycles: 0, latency_total_cycles: 0, max_latency_cycles: 0 }
+    }
+
+    pub fn avg_latency_cycles(&self) -> u64 {
+        if self.polls == 0 {
+            return 0;
+        }
+        return self.latency_total_cycles / self.polls as u64;
+    }
+}
+
+/// Statistics collected by an executor, updated from its interrupt.
+pub(crate) struct ExecutorStatsCell {
+    stats: Mutex<Cell<ExecutorStats>>,
+    pended_at: Mutex<Cell<u64>>,
+}
+
+impl ExecutorStatsCell {
+    pub(crate) const fn new() -> Self {
+        Self { stats: Mutex::new(Cell::new(ExecutorStats::new())), pended_at: Mutex::new(Cell::new(0)) }
+    }
+
+    /// Called from `__pender`, only the first wakeup before a poll counts.
+    pub(crate) fn on_pend(&self) {
+        critical_section::with(|cs| {
+            let pended_at = self.pended_at.borrow(cs);
+            if pended_at.get() == 0 {
+                pended_at.set(timer::uptime_clk());
+            }
+        });
+    }
+
+    /// Called when a poll begins, counts the latency since the first wakeup.
+    pub(crate) fn poll_begin(&self) {
+        critical_section::with(|cs| {
+            let start = timer::uptime_clk();
+            let pended_at = self.pended_at.borrow(cs).replace(0);
+            let latency = if pended_at == 0 { 0 } else { start - pended_at };
+
+            let cell = self.stats.borrow(cs);
+            let mut stats = cell.get();
+            stats.latency_total_cycles += latency;
+            stats.max_latency_cycles = stats.max_latency_cycles.max(latency);
+            cell.set(stats);
+        });
+    }
+
+    /// Called with the busy cycles of the poll from [`BusyStart`].
+    ///
+    /// [`BusyStart`]: crate::executor::BusyStart
+    pub(crate) fn poll_end(&self, busy: u64) {
+        critical_section::with(|cs| {
+            let cell = self.stats.borrow(cs);
+            let mut stats = cell.get();
+            stats.polls = stats.polls.wrapping_add(1);
+            stats.busy_cycles += busy;
+            stats.max_poll_cycles = stats.max_poll_cycles.max(busy);
+            cell.set(stats);
+        });
+    }
+
+    pub(crate) fn snapshot(&self) -> ExecutorStats {
+        critical_section::with(|cs| self.stats.borrow(cs).get())
+    }
+
+    pub(crate) fn reset(&self) {
+        critical_section::with(|cs| self.stats.borrow(cs).set(ExecutorStats::new()));
+    }
+}
+
+/// Statistics of the executor at `priority`.
+pub fn snapshot(priority: Priority) -> ExecutorStats {
+    EXECUTOR_LIST[priority.as_level()].stats.snapshot()
+}
+
+/// Clear the statistics of all levels.
+pub fn reset() {
+    for executor in EXECUTOR_LIST.iter() {
+        executor.stats.reset();
+    }
+}
+
+/// Print the utilization of every started level since the last report, every `period`.
+#[embassy_executor::task]
+pub async fn load_report(period: Duration) {
+    let mut last = [ExecutorStats::new(); MAX_LEVELS];
+    let mut last_time = timer::uptime_clk();
+
+    loop {
+        Timer::after(period).await;
+
+        let now = timer::uptime_clk();
+        let window = (now - last_time).max(1);
+        last_time = now;
+
+        serial::println("Level  Util%   Polls  Max poll us  Avg lat us  Max lat us");
+        for level in 0..MAX_LEVELS {
+            if !EXECUTOR_LIST[level].is_started() {
+                continue;
+            }
+            let stats = EXECUTOR_LIST[level].stats.snapshot();
+            let polls = stats.polls.wrapping_sub(last[level].polls);
+            let busy = stats.busy_cycles - last[level].busy_cycles;
+            let latency = stats.latency_total_cycles - last[level].latency_total_cycles;
+            last[level] = stats;
+
+            print_fmt_func(format_args!("{:>5}  {:>3}.{:01}  {:>6}  {:>11}  {:>10}  {:>10}\n",
+                level,
+                busy * 100 / window,
+                (busy * 1000 / window) % 10,
+                polls,
+                timer::scale_down(stats.max_poll_cycles, timer::CLOCK_HZ, 1_000_000),
+                timer::scale_down(if polls == 0 { 0 } else { latency / polls as u64 }, timer::CLOCK_HZ, 1_000_000),
+                timer::scale_down(stats.max_latency_cycles, timer::CLOCK_HZ, 1_000_000),
+            ));
+        }
+    }
+}
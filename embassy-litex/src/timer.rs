@@ -1,11 +1,19 @@
 #![no_std]
 
 
-use core::task::Waker;
-use core::cell::RefCell;
+use core::task::{Poll, Waker};
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
 
-use crate::{soc_headers::CSR_CPU_TIMER_LATCH_ADDR, interrupt};
-use crate::soc_headers::{CSR_CPU_TIMER_TIME_CMP_LSB, CSR_CPU_TIMER_TIME_CMP_MSB, CSR_CPU_TIMER_TIME_LSB, CSR_CPU_TIMER_TIME_MSB, IRQ_NUM_TIMER0};
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_time::Duration;
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::{interrupt, soc_headers};
+#[cfg(not(feature = "time-driver-timer0"))]
+use crate::soc_headers::{CSR_CPU_TIMER_LATCH_ADDR, CSR_CPU_TIMER_TIME_CMP_LSB, CSR_CPU_TIMER_TIME_CMP_MSB, CSR_CPU_TIMER_TIME_LSB, CSR_CPU_TIMER_TIME_MSB};
+#[cfg(feature = "time-driver-timer0")]
+use crate::soc_headers::{CSR_TIMER0_BASE_ADDR, IRQ_NUM_TIMER0};
 
 use crate::serial::println;
 use crate::serial;
@@ -17,9 +25,74 @@ use embassy_time_queue_utils::Queue;
 
 use critical_section::{CriticalSection, Mutex};
 
+/// Frequency of `sys_clk`, the rate of every cycle counter in the SoC.
+///
+/// Written to the SoC headers by `generate_rust_headers` in `sim.py`.
+pub const CLOCK_HZ: u64 = soc_headers::CONFIG_CLOCK_FREQUENCY as u64;
+
+const _: () = assert!(CLOCK_HZ > 0, "CONFIG_CLOCK_FREQUENCY is 0 in the SoC headers");
+const _: () = assert!(TICK_HZ <= CLOCK_HZ, "embassy-time tick rate is higher than sys_clk, select a lower tick-hz-* feature");
+
+const fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        return a;
+    }
+    return gcd(b, a % b);
+}
+
+/// Convert `value` counted at `from_hz` to `to_hz`, rounding down.
+///
+/// Splits the value into whole seconds and remainder so the intermediate
+/// products stay below 64 bits for any clock below 4 GHz.
+#[inline(always)]
+pub const fn scale_down(value: u64, from_hz: u64, to_hz: u64) -> u64 {
+    let g = gcd(from_hz, to_hz);
+    let (from, to) = (from_hz / g, to_hz / g);
+    if from == to {
+        return value;
+    }
+    let whole = (value / from).saturating_mul(to);
+    return whole.saturating_add((value % from) * to / from);
+}
+
+/// Convert `value` counted at `from_hz` to `to_hz`, rounding up.
+///
+/// Saturates at `u64::MAX`, so "never" stays "never".
+#[inline(always)]
+pub const fn scale_up(value: u64, from_hz: u64, to_hz: u64) -> u64 {
+    let g = gcd(from_hz, to_hz);
+    let (from, to) = (from_hz / g, to_hz / g);
+    if from == to {
+        return value;
+    }
+    let whole = (value / from).saturating_mul(to);
+    return whole.saturating_add(((value % from) * to + from - 1) / from);
+}
+
+/// Convert `sys_clk` cycles to embassy-time ticks.
+#[inline(always)]
+pub const fn cycles_to_ticks(cycles: u64) -> u64 {
+    scale_down(cycles, CLOCK_HZ, TICK_HZ)
+}
+
+/// Convert embassy-time ticks to `sys_clk` cycles, an alarm never fires early.
+#[inline(always)]
+pub const fn ticks_to_cycles(ticks: u64) -> u64 {
+    scale_up(ticks, TICK_HZ, CLOCK_HZ)
+}
+
+/// Driver for the LiteX `Timer` peripheral.
+///
+/// The timer counts down from `load` in `sys_clk` cycles and raises the zero
+/// event when it reaches zero. With a non-zero `reload` it restarts from that
+/// value, which gives a periodic event without any software drift.
+///
+/// Call [`Timer::init`] once to route the zero event to [`Timer::wait`].
 pub struct Timer {
     pub registers: TimerRegisters,
-    pub initialized:bool
+    pub initialized:bool,
+    waker: AtomicWaker,
+    events: AtomicU32,
 }
 
 struct TimerRegisters {
@@ -50,8 +123,18 @@ struct TimerRegisters {
 
 impl Timer {
     
+    /// Timer with its CSRs at `base_addr`.
+    ///
+    /// With `time-driver-timer0` Timer0 belongs to the time driver, creating it
+    /// fails, at compile time in a `static`.
     pub const fn create_timer(base_addr:u32) -> Timer {
-        let mut s = Timer { initialized:false, registers :TimerRegisters { 
+        #[cfg(feature = "time-driver-timer0")]
+        assert!(base_addr != CSR_TIMER0_BASE_ADDR, "Timer0 is used by the time driver with the time-driver-timer0 feature");
+        return Self::new(base_addr);
+    }
+
+    const fn new(base_addr:u32) -> Timer {
+        let mut s = Timer { initialized:false, waker: AtomicWaker::new(), events: AtomicU32::new(0), registers :TimerRegisters { 
             load:           Register { addr: base_addr + 0x00 }, 
             reload:         Register { addr: base_addr + 0x04 }, 
             en:             Register { addr: base_addr + 0x08 }, 
@@ -73,28 +156,92 @@ impl Timer {
         return s;
     }
 
-    pub fn enable_irq(&self){
+    /// Route the zero event of the timer to `function`.
+    ///
+    /// The handler has to acknowledge the event with [`Timer::clear_event`].
+    pub fn enable_irq(&self, irq_num:usize, function:fn(ctx:*mut ())->(), context:*mut ()){
         //println("TIMER enable irq");
-        //self.registers.en.write(0);
-        //self.registers.reload.write(0);
-        //interrupt::ExternalInterrupt::register_interrupt(IRQ_NUM_TIMER0, external_interrupt_timer, 0 as *mut ());
-        //self.registers.ev_pending.write(u32::MAX);
-        //self.registers.ev_enable.write(1);
+        self.registers.ev_enable.write(0);
+        self.registers.ev_pending.write(u32::MAX);
+        interrupt::ExternalInterrupt::register_interrupt(irq_num, function, context);
+        self.registers.ev_enable.write(1);
     }
 
-    pub fn stop(&self){
+    /// Route the zero event to [`Timer::wait`].
+    ///
+    /// The timer must live forever, the interrupt keeps a pointer to it.
+    pub fn init(&'static self, irq_num:usize){
+        let ptr = self as *const Self as *mut ();
+        self.enable_irq(irq_num, external_interrupt_timer_event, ptr);
+    }
 
+    pub fn clear_event(&self){
+        self.registers.ev_pending.write(1);
     }
 
+    pub fn stop(&self){
+        self.registers.en.write(0);
+        self.clear_event();
+        self.events.store(0, Ordering::Relaxed);
+    }
+
+    /// One-shot mode, the zero event fires once after `timeout` cycles.
     pub fn start(&self, timeout:u32){
         //println("TIMER start");
         self.registers.en.write(0);
         self.registers.reload.write(0);
         self.registers.load.write(timeout);
+        self.clear_event();
+        self.events.store(0, Ordering::Relaxed);
+        self.registers.en.write(1);
+        self.registers.update_value.write(1);
+    }
+
+    /// Periodic mode, the zero event fires every `period` cycles.
+    pub fn start_periodic(&self, period:u32){
+        self.registers.en.write(0);
+        self.registers.reload.write(period);
+        self.registers.load.write(period);
+        self.clear_event();
+        self.events.store(0, Ordering::Relaxed);
         self.registers.en.write(1);
         self.registers.update_value.write(1);
     }
 
+    pub fn is_running(&self) -> bool {
+        return self.registers.en.read() != 0;
+    }
+
+    /// Current countdown value in cycles.
+    pub fn value(&self) -> u32 {
+        self.registers.update_value.write(1);
+        return self.registers.value.read();
+    }
+
+    /// Wait for the next zero event.
+    ///
+    /// Events are counted, in periodic mode every period is returned once even
+    /// if the waiting task was late.
+    pub async fn wait(&self) {
+        poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            if self.take_event() {
+                return Poll::Ready(());
+            }
+            return Poll::Pending;
+        }).await
+    }
+
+    /// Consume one zero event without waiting.
+    pub fn take_event(&self) -> bool {
+        return self.events.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1)).is_ok();
+    }
+
+    /// Number of zero events not consumed by [`Timer::wait`] yet.
+    pub fn pending_events(&self) -> u32 {
+        return self.events.load(Ordering::Relaxed);
+    }
+
 
     pub fn uptime_clk(&self)->u64 {
         self.registers.uptime_latch.write(0x01);
@@ -108,7 +255,7 @@ impl Timer {
 
     pub fn uptime_micros(&self)->u64 {
         let clk = self.uptime_clk();
-        let micros:u64 = (clk *TICK_HZ) / 1_000_000;
+        let micros:u64 = scale_down(clk, CLOCK_HZ, 1_000_000);
         return micros;
     }
 
@@ -117,6 +264,49 @@ impl Timer {
 
 
 
+fn external_interrupt_timer_event(ctx:*mut ()) {
+    let timer = unsafe { &*(ctx as *const Timer) };
+    timer.clear_event();
+    timer.events.fetch_add(1, Ordering::AcqRel);
+    timer.waker.wake();
+}
+
+
+/// Periodic stream of hardware timer events.
+///
+/// Unlike [`embassy_time::Ticker`] the period is generated by the timer reload,
+/// a late task does not shift the following ticks.
+pub struct HardwareTicker {
+    timer: &'static Timer,
+}
+
+impl HardwareTicker {
+    /// Start `timer` in periodic mode, the timer must be initialized with [`Timer::init`].
+    pub fn every(timer: &'static Timer, period: Duration) -> Self {
+        let cycles = ticks_to_cycles(period.as_ticks());
+        timer.start_periodic(cycles.clamp(1, u32::MAX as u64) as u32);
+        return Self { timer };
+    }
+
+    /// Wait for the next period.
+    pub async fn next(&mut self) {
+        self.timer.wait().await
+    }
+
+    /// Periods which elapsed without a call to [`HardwareTicker::next`].
+    pub fn missed(&self) -> u32 {
+        return self.timer.pending_events();
+    }
+}
+
+impl Drop for HardwareTicker {
+    fn drop(&mut self) {
+        self.timer.stop();
+    }
+}
+
+
+#[cfg(not(feature = "time-driver-timer0"))]
 struct VexRiscvTimer {
     pub latch:Register,
     pub time_lsb:Register,
@@ -125,6 +315,7 @@ struct VexRiscvTimer {
     pub compare_msb:Register,
 }
 
+#[cfg(not(feature = "time-driver-timer0"))]
 impl VexRiscvTimer {
     pub const fn create() -> Self {
         return Self {
@@ -165,26 +356,93 @@ impl VexRiscvTimer {
 
     pub fn uptime_micros(&self)->u64 {
         let clk = self.uptime_clk();
-        let micros:u64 = (clk *TICK_HZ) / 1_000_000;
+        let micros:u64 = scale_down(clk, CLOCK_HZ, 1_000_000);
         return micros;
     }
 
     
 }
 
+#[cfg(not(feature = "time-driver-timer0"))]
 static INTERNAL_TIMER:VexRiscvTimer = VexRiscvTimer::create();
 
+/// Timer0 drives the time driver on CPUs without the VexRiscv machine timer.
+#[cfg(feature = "time-driver-timer0")]
+static INTERNAL_TIMER:Timer = Timer::new(CSR_TIMER0_BASE_ADDR);
+
+// the uptime CSRs only exist with `timer_uptime` in the SoC, without them the
+// constant is missing and the build fails here instead of reading unmapped CSRs
+#[cfg(feature = "time-driver-timer0")]
+const _: () = assert!(soc_headers::CSR_TIMER0_UPTIME_CYCLES_ADDR == CSR_TIMER0_BASE_ADDR + 0x24, "unexpected Timer0 uptime CSR layout");
+
+/// Current `sys_clk` cycle count of the timer backing the time driver.
+pub fn uptime_clk() -> u64 {
+    INTERNAL_TIMER.uptime_clk()
+}
+
+/// Route the Timer0 zero event to the time driver.
+#[cfg(feature = "time-driver-timer0")]
+pub(crate) fn init() {
+    INTERNAL_TIMER.stop();
+    INTERNAL_TIMER.enable_irq(IRQ_NUM_TIMER0, external_interrupt_timer, 0 as *mut ());
+}
+
+#[cfg(feature = "time-driver-timer0")]
+fn external_interrupt_timer(_:*mut ()) {
+    INTERNAL_TIMER.clear_event();
+    DRIVER.on_interrupt();
+}
+
 
 
 
+#[cfg(all(feature = "generic-queue", feature = "integrated-timer-queue"))]
+compile_error!("features `generic-queue` and `integrated-timer-queue` are mutually exclusive, disable the default features");
+#[cfg(not(any(feature = "generic-queue", feature = "integrated-timer-queue")))]
+compile_error!("enable one timer queue: `generic-queue` or `integrated-timer-queue`");
+
+// no AtomicU64, its fallback lock deadlocks when the alarm interrupt takes it
+static NEXT_ALARM: Mutex<Cell<u64>> = Mutex::new(Cell::new(u64::MAX));
+
+/// Tick of the next armed alarm of the time driver, `u64::MAX` if none.
+pub fn next_alarm() -> u64 {
+    return critical_section::with(|cs| NEXT_ALARM.borrow(cs).get());
+}
+
+/// embassy-time driver.
+///
+/// With `integrated-timer-queue` the queue only links the expiration times
+/// stored in the tasks themselves, every waker passed in has to belong to an
+/// embassy-executor task.
 struct TimeDriver {
     queue: Mutex<RefCell<Queue>>,
 }
 
 impl TimeDriver {
+    #[cfg(not(feature = "time-driver-timer0"))]
+    fn set_alarm(&self, cs: &CriticalSection, timestamp: u64) -> bool {
+        if timestamp > self.now() {
+            INTERNAL_TIMER.set(ticks_to_cycles(timestamp));
+            NEXT_ALARM.borrow(*cs).set(timestamp);
+            return true;
+        }else{
+            return false;
+        }
+    }
+
+    #[cfg(feature = "time-driver-timer0")]
     fn set_alarm(&self, cs: &CriticalSection, timestamp: u64) -> bool {
+        if timestamp == u64::MAX {
+            INTERNAL_TIMER.stop();
+            NEXT_ALARM.borrow(*cs).set(timestamp);
+            return true;
+        }
         if timestamp > self.now() {
-            INTERNAL_TIMER.set(timestamp);
+            // alarms beyond the 32 bit countdown fire early and are re-armed
+            let cycles = ticks_to_cycles(timestamp).saturating_sub(INTERNAL_TIMER.uptime_clk()).max(1);
+            let timeout = cycles.min(u32::MAX as u64) as u32;
+            INTERNAL_TIMER.start(timeout);
+            NEXT_ALARM.borrow(*cs).set(timestamp);
             return true;
         }else{
             return false;
@@ -206,7 +464,7 @@ impl TimeDriver {
 
 impl Driver for TimeDriver {
     fn now(&self) -> u64 {
-        return INTERNAL_TIMER.uptime_clk();
+        return cycles_to_ticks(INTERNAL_TIMER.uptime_clk());
     }
 
     fn schedule_wake(&self, at: u64, waker: &Waker) {
@@ -233,5 +491,7 @@ embassy_time_driver::time_driver_impl!(static DRIVER: TimeDriver = TimeDriver{ q
 #[allow(non_snake_case)]
 #[export_name = "MachineTimerInterruptHandler"]
 pub extern "Rust" fn MachineTimerInterruptHandler(level: u32, interrupt: usize) {
-    DRIVER.on_interrupt();
+    // with Timer0 the alarm arrives as external interrupt
+    #[cfg(not(feature = "time-driver-timer0"))]
+    interrupt::as_handler(|| DRIVER.on_interrupt());
 }
@@ -1,94 +1,15 @@
 
-use core::arch::asm;
+use crate::serial::print_fmt_func;
+#[cfg(feature = "task-monitor")]
+use crate::monitor;
+#[cfg(feature = "poll-watchdog")]
+use crate::watchdog;
 
-use crate::serial::{self, print_fmt_func};
-
-
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    riscv::interrupt::disable();
-
-    serial::println("");
-    serial::println("");
-    serial::println("");
-    serial::println("-------------- Panic ------------");
-    serial::print_fmt_func(format_args!("{}\r\n", _info));
-    serial::println("Stopping CPU.");
-    serial::println("Trace:");
-
-    let mut fp = unsafe {
-        let mut _tmp: u32;
-        asm!("mv {0}, x8", out(reg) _tmp);
-        _tmp
-    };
-
-    let mut sp = unsafe {
-        let mut _tmp: u32;
-        asm!("mv {0}, x2", out(reg) _tmp);
-        _tmp
-    };
-
-    
-
-    loop {
-
-        if !is_addr_aligned(fp) {
-            serial::print_fmt_func(format_args!("Address {:08x} unaligned...", fp));
-            break;
-        }
-    
-        if !is_addr_valid(fp) {
-            serial::print_fmt_func(format_args!("Address {:08x} not in mem...",fp));
-            break;
-        }
-
-        // RA/PC
-        let address = unsafe { (fp as *const u32).offset(-1).read_volatile() };
-        // next FP
-        fp = unsafe { (fp as *const u32).offset(-2).read_volatile() };
-
-        if address == 0 {
-            serial::println("PC Addr == 0 ....");
-            break;
-        }
-
-
-        serial::print_fmt_func(format_args!("PC ADDR: {:08x}",address));
-
-        let raw_ptr = address as *mut TrapFrame;
-        let rust_reference: &mut TrapFrame = unsafe{ raw_ptr.as_mut().unwrap() };
-        rust_reference.print();
-        
-    }
-    
-
-
-
-
-    loop {}
-}
-
-
-
-
-fn is_addr_aligned(address: u32) -> bool {
-    if (address & 0xF) != 0 {
-        return false;
-    }
-    return true;
-}
-
-fn is_addr_valid(address: u32) -> bool {
-    // TODO implement auto 
-    if !(0x1000_0000..=0x1002_0000).contains(&address) {
-        return false;
-    }
-
-    true
-}
 
 
+/// Register state at a trap, filled by the exception entry, see [`exception`](crate::exception).
+#[repr(C)]
+#[derive(Clone, Copy)]
 pub struct TrapFrame {
     /// Return address, stores the address to return to after a function call or
     /// interrupt.
@@ -177,6 +98,7 @@ pub struct TrapFrame {
 }
 
 impl TrapFrame {
+    #[cfg(not(feature = "panic-minimal"))]
     pub fn print (&self){
         print_fmt_func(format_args!("
 TrapFrame
@@ -194,8 +116,8 @@ MTVAL=0x{:08x}
 ",
 self.pc,
 self.ra,
-self.gp,
 self.sp,
+self.gp,
 self.tp,
 self.t0,
 self.t1,
@@ -228,6 +150,32 @@ self.mstatus,
 self.mcause,
 self.mtval,));
     }
+
+    /// Print the registers without `core::fmt`, in the order of the full print.
+    #[cfg(feature = "panic-minimal")]
+    pub fn print(&self) {
+        use crate::panic::minimal::print_hex;
+        use crate::serial;
+
+        let registers = [
+            ("PC", self.pc), ("RA/x1", self.ra), ("SP/x2", self.sp), ("GP/x3", self.gp), ("TP/x4", self.tp),
+            ("T0/x5", self.t0), ("T1/x6", self.t1), ("T2/x7", self.t2), ("S0/FP/x8", self.s0), ("S1/x9", self.s1),
+            ("A0/x10", self.a0), ("A1/x11", self.a1), ("A2/x12", self.a2), ("A3/x13", self.a3), ("A4/x14", self.a4),
+            ("A5/x15", self.a5), ("A6/x16", self.a6), ("A7/x17", self.a7), ("S2/x18", self.s2), ("S3/x19", self.s3),
+            ("S4/x20", self.s4), ("S5/x21", self.s5), ("S6/x22", self.s6), ("S7/x23", self.s7), ("S8/x24", self.s8),
+            ("S9/x25", self.s9), ("S10/x26", self.s10), ("S11/x27", self.s11), ("T3/x28", self.t3), ("T4/x29", self.t4),
+            ("T5/x30", self.t5), ("T6/x31", self.t6),
+            ("MSTATUS", self.mstatus), ("MCAUSE", self.mcause), ("MTVAL", self.mtval),
+        ];
+        serial::println("");
+        serial::println("TrapFrame");
+        for (n, (name, value)) in registers.iter().enumerate() {
+            serial::print(name);
+            serial::print("=");
+            print_hex(*value);
+            serial::print(if n % 5 == 4 || n == registers.len() - 1 { "\n" } else { "     " });
+        }
+    }
 }
 
 
@@ -237,35 +185,56 @@ self.mtval,));
 // Executor trace
 #[no_mangle]
 fn _embassy_trace_poll_start(executor_id: u32){
+    #[cfg(not(any(feature = "task-monitor", feature = "poll-watchdog")))]
     print_fmt_func(format_args!("_embassy_trace_poll_start {:#08x}\n",executor_id));
 }
 
 #[no_mangle]
 fn _embassy_trace_task_new(executor_id: u32, task_id: u32){
+    #[cfg(feature = "task-monitor")]
+    monitor::task_new(executor_id as usize, task_id);
+    #[cfg(not(any(feature = "task-monitor", feature = "poll-watchdog")))]
     print_fmt_func(format_args!("_embassy_trace_task_new {:#08x}, {:#08x}\n",executor_id, task_id));
 }
 
 #[no_mangle]
 fn _embassy_trace_task_end(executor_id: u32, task_id: u32){
-    print_fmt_func(format_args!("_embassy_trace_task_new {:#08x}, {:#08x}\n",executor_id, task_id));
+    #[cfg(feature = "task-monitor")]
+    monitor::task_end(executor_id as usize, task_id);
+    #[cfg(not(any(feature = "task-monitor", feature = "poll-watchdog")))]
+    print_fmt_func(format_args!("_embassy_trace_task_end {:#08x}, {:#08x}\n",executor_id, task_id));
 }
 
 #[no_mangle]
 fn _embassy_trace_task_exec_begin(executor_id: u32, task_id: u32){
-    print_fmt_func(format_args!("_embassy_trace_task_new {:#08x}, {:#08x}\n",executor_id, task_id));
+    #[cfg(feature = "task-monitor")]
+    monitor::task_exec_begin(executor_id as usize, task_id);
+    #[cfg(feature = "poll-watchdog")]
+    watchdog::task_exec_begin(task_id);
+    #[cfg(not(any(feature = "task-monitor", feature = "poll-watchdog")))]
+    print_fmt_func(format_args!("_embassy_trace_task_exec_begin {:#08x}, {:#08x}\n",executor_id, task_id));
 }
 
 #[no_mangle]
 fn _embassy_trace_task_exec_end(executor_id: u32, task_id: u32){
-    print_fmt_func(format_args!("_embassy_trace_task_new {:#08x}, {:#08x}\n",executor_id, task_id));
+    #[cfg(feature = "task-monitor")]
+    monitor::task_exec_end(executor_id as usize, task_id);
+    #[cfg(feature = "poll-watchdog")]
+    watchdog::task_exec_end(task_id);
+    #[cfg(not(any(feature = "task-monitor", feature = "poll-watchdog")))]
+    print_fmt_func(format_args!("_embassy_trace_task_exec_end {:#08x}, {:#08x}\n",executor_id, task_id));
 }
 
 #[no_mangle]
 fn _embassy_trace_task_ready_begin(executor_id: u32, task_id: u32){
-    print_fmt_func(format_args!("_embassy_trace_task_new {:#08x}, {:#08x}\n",executor_id, task_id));
+    #[cfg(feature = "task-monitor")]
+    monitor::task_ready_begin(executor_id as usize, task_id);
+    #[cfg(not(any(feature = "task-monitor", feature = "poll-watchdog")))]
+    print_fmt_func(format_args!("_embassy_trace_task_ready_begin {:#08x}, {:#08x}\n",executor_id, task_id));
 }
 
 #[no_mangle]
 fn _embassy_trace_executor_idle(executor_id: u32){
-    print_fmt_func(format_args!("_embassy_trace_poll_start {:#08x}\n",executor_id));
-}
\ No newline at end of file
+    #[cfg(not(any(feature = "task-monitor", feature = "poll-watchdog")))]
+    print_fmt_func(format_args!("_embassy_trace_executor_idle {:#08x}\n",executor_id));
+}
@@ -0,0 +1,124 @@
+//! Long-poll detector for the interrupt executors.
+//!
+//! A task that runs for a long time without awaiting blocks all other tasks of
+//! its level and of all lower levels. With the `poll-watchdog` feature every
+//! poll of a task on an interrupt executor level is timed with the cycle timer
+//! and compared against the budget of its level. An overrun is printed with the
+//! task id and the poll duration, or panics if [`Action::Panic`] is selected.
+//!
+//! Time spent in higher levels while the task was preempted does not count
+//! against its budget. Tasks of the thread-mode executor are not checked.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embassy_time::Duration;
+use portable_atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::executor::{BusyStart, Priority, MAX_LEVELS};
+use crate::serial::print_fmt_func;
+use crate::timer;
+
+/// What happens when a poll exceeds its budget.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Print the task id and poll duration and continue.
+    Log,
+    /// Panic with the task id and poll duration.
+    Panic,
+}
+
+/// Budget of every level unless changed by [`set_budget`].
+pub const DEFAULT_BUDGET: Duration = Duration::from_millis(10);
+
+const NO_LEVEL: usize = usize::MAX;
+
+/// Level of the executor currently polling, set by `on_interrupt`.
+static CURRENT_LEVEL: AtomicUsize = AtomicUsize::new(NO_LEVEL);
+
+static BUDGETS: Mutex<Cell<[u64; MAX_LEVELS]>> = Mutex::new(Cell::new([0; MAX_LEVELS]));
+static ACTION: Mutex<Cell<Action>> = Mutex::new(Cell::new(Action::Log));
+
+/// Start of the running task poll, per level.
+static POLL_START: Mutex<Cell<[BusyStart; MAX_LEVELS]>> = Mutex::new(Cell::new([BusyStart::NONE; MAX_LEVELS]));
+
+static OVERRUNS: AtomicU32 = AtomicU32::new(0);
+
+
+/// Set the maximum duration of a single task poll at `priority`.
+pub fn set_budget(priority: Priority, budget: Duration) {
+    critical_section::with(|cs| {
+        let cell = BUDGETS.borrow(cs);
+        let mut budgets = cell.get();
+        budgets[priority.as_level()] = timer::ticks_to_cycles(budget.as_ticks());
+        cell.set(budgets);
+    });
+}
+
+/// Set the reaction on an overrun for all levels.
+pub fn set_action(action: Action) {
+    critical_section::with(|cs| ACTION.borrow(cs).set(action));
+}
+
+/// Number of polls which exceeded their budget.
+pub fn overruns() -> u32 {
+    return OVERRUNS.load(Ordering::Relaxed);
+}
+
+fn budget(cs: critical_section::CriticalSection, level: usize) -> u64 {
+    let budget = BUDGETS.borrow(cs).get()[level];
+    if budget == 0 {
+        return timer::ticks_to_cycles(DEFAULT_BUDGET.as_ticks());
+    }
+    return budget;
+}
+
+/// Called by `on_interrupt` before polling, returns the level to restore.
+#[inline(always)]
+pub(crate) fn enter(level: usize) -> usize {
+    CURRENT_LEVEL.swap(level, Ordering::Relaxed)
+}
+
+/// Called by `on_interrupt` after polling with the result of [`enter`].
+#[inline(always)]
+pub(crate) fn exit(previous: usize) {
+    CURRENT_LEVEL.store(previous, Ordering::Relaxed);
+}
+
+pub(crate) fn task_exec_begin(_task_id: u32) {
+    let level = CURRENT_LEVEL.load(Ordering::Relaxed);
+    if level == NO_LEVEL {
+        return;
+    }
+    critical_section::with(|cs| {
+        let cell = POLL_START.borrow(cs);
+        let mut starts = cell.get();
+        starts[level] = BusyStart::now(cs);
+        cell.set(starts);
+    });
+}
+
+pub(crate) fn task_exec_end(task_id: u32) {
+    let level = CURRENT_LEVEL.load(Ordering::Relaxed);
+    if level == NO_LEVEL {
+        return;
+    }
+    let (busy, budget, action) = critical_section::with(|cs| {
+        let busy = POLL_START.borrow(cs).get()[level].busy_cycles(cs);
+        (busy, budget(cs, level), ACTION.borrow(cs).get())
+    });
+
+    if busy <= budget {
+        return;
+    }
+    OVERRUNS.fetch_add(1, Ordering::Relaxed);
+
+    let busy_us = timer::scale_down(busy, timer::CLOCK_HZ, 1_000_000);
+    let budget_us = timer::scale_down(budget, timer::CLOCK_HZ, 1_000_000);
+    match action {
+        Action::Log => print_fmt_func(format_args!("Long poll: level {} task {:#010x} took {} us, budget {} us\n",
+            level, task_id, busy_us, budget_us)),
+        Action::Panic => panic!("Long poll: level {} task {:#010x} took {} us, budget {} us",
+            level, task_id, busy_us, budget_us),
+    }
+}
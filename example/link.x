@@ -96,6 +96,16 @@ SECTIONS
   . = ALIGN(8);
   __ebss = .;
 
+  /* Not zeroed or loaded at boot, keeps its content across a soft reset,
+   * e.g. the crash record of embassy-litex. */
+  .uninit (NOLOAD) : ALIGN(8)
+  {
+    __suninit = .;
+    *(.uninit .uninit.*);
+    . = ALIGN(8);
+    __euninit = .;
+  } > REGION_BSS
+
 
 /* fictitious region that represents the memory available for the stack */
   .stack (NOLOAD) : ALIGN(1M)
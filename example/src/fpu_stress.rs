@@ -0,0 +1,70 @@
+//! Stress test for the FPU context save of preempted executor levels.
+//!
+//! Each level repeatedly runs the same float computation while the higher
+//! levels keep preempting it with their own float work. Without the lazy FPU
+//! save the caller-saved float registers of a preempted level get overwritten
+//! and the result differs from the reference computed at start.
+
+use embassy_litex::executor::{self, Priority};
+use embassy_litex::serial::{self, print_fmt_func};
+use embassy_time::{Duration, Timer};
+
+use portable_atomic::{AtomicU32, Ordering};
+
+const LEVELS: [Priority; 3] = [Priority::level::<4>(), Priority::level::<8>(), Priority::level::<12>()];
+const PERIODS_US: [u64; 3] = [500, 170, 60];
+
+static ITERATIONS: [AtomicU32; 3] = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+
+// use the widest type the FPU handles in hardware
+#[cfg(target_feature = "d")]
+type Float = f64;
+#[cfg(not(target_feature = "d"))]
+type Float = f32;
+
+
+#[inline(never)]
+fn series(start: Float, rounds: u32) -> Float {
+    let mut x = start;
+    let mut sum = 0.0;
+    for i in 0..rounds {
+        x = x * 1.000_001 + 0.5 / (i as Float + 1.0);
+        sum += x / (1.0 + x * x);
+    }
+    return sum;
+}
+
+#[embassy_executor::task(pool_size = 3)]
+async fn stress(slot: usize) {
+    let start = 0.25 + slot as Float;
+    let rounds = 3000 - 800 * slot as u32;
+    let expected = series(start, rounds);
+
+    loop {
+        let result = series(start, rounds);
+        if result.to_bits() != expected.to_bits() {
+            panic!("FPU stress: level {} result {} != {}", LEVELS[slot].as_level(), result, expected);
+        }
+        ITERATIONS[slot].fetch_add(1, Ordering::Relaxed);
+        Timer::after(Duration::from_micros(PERIODS_US[slot])).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn report() {
+    loop {
+        Timer::after(Duration::from_secs(2)).await;
+        serial::print("FPU stress:");
+        for slot in 0..LEVELS.len() {
+            print_fmt_func(format_args!("  L{}={}", LEVELS[slot].as_level(), ITERATIONS[slot].load(Ordering::Relaxed)));
+        }
+        serial::print("\n");
+    }
+}
+
+pub fn start() {
+    for slot in 0..LEVELS.len() {
+        executor::start(LEVELS[slot]).must_spawn(stress(slot));
+    }
+    executor::start(Priority::level::<0>()).must_spawn(report());
+}
@@ -20,8 +20,15 @@ use embedded_alloc::LlffHeap as Heap;
 static HEAP: Heap = Heap::empty();
 
 
-// use for macros
-use embassy_litex::executor::EXECUTOR_LIST;
+// interrupt executor levels, main itself runs in the thread-mode executor
+use embassy_litex::executor::{self, Priority};
+
+#[cfg(feature = "stress-nesting")]
+mod nesting_stress;
+#[cfg(feature = "bench-timer-queue")]
+mod timer_queue_bench;
+#[cfg(feature = "stress-fpu")]
+mod fpu_stress;
 
 
 
@@ -65,6 +72,14 @@ async fn processing() {
 
 
 
+#[cfg(feature = "stack-usage")]
+#[embassy_executor::task]
+async fn stack_report() {
+    loop {
+        Timer::after(Duration::from_secs(5)).await;
+        embassy_litex::stack::report();
+    }
+}
 
 
 #[embassy_litex::executor::main]
@@ -77,6 +92,12 @@ async fn main()  {
 
     serial::init();
 
+    #[cfg(feature = "crash-record")]
+    match embassy_litex::crash::take() {
+        Some(record) => record.print(),
+        None => serial::println("No crash record."),
+    }
+
     extern {
         static _stack_start: *mut u8;
         static __sstack: *mut u8;
@@ -103,12 +124,30 @@ async fn main()  {
     serial::println("Heap initialized...");
 
 
-    let spawner = EXECUTOR_LIST[10].start() ;
+    let spawner = executor::start(Priority::level::<10>());
     spawner.must_spawn(blink());
 
-    let spawner =  EXECUTOR_LIST[3].start() ;
+    #[cfg(feature = "poll-watchdog")]
+    embassy_litex::watchdog::set_budget(Priority::level::<3>(), Duration::from_millis(1));
+
+    let spawner = executor::start(Priority::level::<3>());
     spawner.must_spawn(processing());
 
+    #[cfg(feature = "stress-nesting")]
+    nesting_stress::start();
+
+    #[cfg(feature = "bench-timer-queue")]
+    timer_queue_bench::start();
+
+    #[cfg(feature = "stress-fpu")]
+    fpu_stress::start();
+
+    #[cfg(feature = "stack-usage")]
+    Spawner::for_current_executor().await.must_spawn(stack_report());
+
+    #[cfg(feature = "task-monitor")]
+    Spawner::for_current_executor().await.must_spawn(embassy_litex::monitor::top(Duration::from_secs(5)));
+
 
     loop {
         let a = serial::read().await;
@@ -0,0 +1,64 @@
+//! Stress test for nested executor levels.
+//!
+//! Every stressed level runs a task that repeatedly computes the same checksum
+//! while the timers of the higher levels keep preempting it. A corrupted
+//! `mepc`/`mstatus` on return from a nested trap either crashes the CPU or
+//! shows up as a checksum mismatch. A reporter on the lowest level prints the
+//! iteration count per level, a level that stops counting hangs.
+
+use embassy_litex::executor::{self, Priority};
+use embassy_litex::serial::{self, print_fmt_func};
+use embassy_time::{Duration, Timer};
+
+use portable_atomic::{AtomicU32, Ordering};
+
+const LEVELS: [Priority; 4] = [Priority::level::<2>(), Priority::level::<6>(), Priority::level::<9>(), Priority::level::<13>()];
+const PERIODS_US: [u64; 4] = [700, 300, 110, 50];
+
+static ITERATIONS: [AtomicU32; 4] = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+
+
+fn checksum(seed: u32, rounds: u32) -> u32 {
+    let mut x = seed;
+    let mut sum: u32 = 0;
+    for _ in 0..rounds {
+        x = x.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        sum = sum.rotate_left(5) ^ x;
+    }
+    return sum;
+}
+
+#[embassy_executor::task(pool_size = 4)]
+async fn stress(slot: usize) {
+    let seed = 0x1234_5678 + slot as u32;
+    let rounds = 2000 - 400 * slot as u32;
+    let expected = checksum(seed, rounds);
+
+    loop {
+        let result = checksum(seed, rounds);
+        if result != expected {
+            panic!("Nesting stress: level {} checksum {:08x} != {:08x}", LEVELS[slot].as_level(), result, expected);
+        }
+        ITERATIONS[slot].fetch_add(1, Ordering::Relaxed);
+        Timer::after(Duration::from_micros(PERIODS_US[slot])).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn report() {
+    loop {
+        Timer::after(Duration::from_secs(2)).await;
+        serial::print("Nesting stress:");
+        for slot in 0..LEVELS.len() {
+            print_fmt_func(format_args!("  L{}={}", LEVELS[slot].as_level(), ITERATIONS[slot].load(Ordering::Relaxed)));
+        }
+        serial::print("\n");
+    }
+}
+
+pub fn start() {
+    for slot in 0..LEVELS.len() {
+        executor::start(LEVELS[slot]).must_spawn(stress(slot));
+    }
+    executor::start(Priority::level::<1>()).must_spawn(report());
+}
@@ -0,0 +1,85 @@
+//! Timer queue benchmark.
+//!
+//! Spawns a few hundred tasks which all sleep on short, different periods and
+//! measures how late they are woken. Build once with the default generic queue
+//! and once with `--no-default-features --features integrated-timer-queue` to
+//! compare both time driver modes. A full generic queue wakes timers early to
+//! make room, that shows up as more wakeups than requested and higher lateness.
+
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+
+use embassy_litex::executor::{self, Priority};
+use embassy_litex::serial::print_fmt_func;
+use embassy_litex::timer;
+use embassy_time::{Duration, Instant, Timer};
+
+use portable_atomic::{AtomicU32, Ordering};
+
+const TASKS: usize = 256;
+const REPORT_PERIOD: Duration = Duration::from_secs(2);
+
+static WAKEUPS: AtomicU32 = AtomicU32::new(0);
+static POLLS: AtomicU32 = AtomicU32::new(0);
+// 32 bit only, the 64 bit atomics use a spinlock which deadlocks across interrupt levels
+static LATENESS_SUM: AtomicU32 = AtomicU32::new(0);
+static LATENESS_MAX: AtomicU32 = AtomicU32::new(0);
+static SCHEDULE_CYCLES: AtomicU32 = AtomicU32::new(0);
+
+
+#[embassy_executor::task(pool_size = TASKS)]
+async fn sleeper(n: usize) {
+    // periods between 1 and 8 ms, spread so that the queue never drains
+    let period = Duration::from_micros(1000 + (n as u64 * 7919) % 7000);
+    loop {
+        let deadline = Instant::now() + period;
+
+        // the first poll of the timer inserts it into the queue
+        let mut sleep = Timer::at(deadline);
+        let mut first = true;
+        poll_fn(|cx| {
+            let start = timer::uptime_clk();
+            let result = Pin::new(&mut sleep).poll(cx);
+            if first {
+                SCHEDULE_CYCLES.fetch_add((timer::uptime_clk() - start) as u32, Ordering::Relaxed);
+                POLLS.fetch_add(1, Ordering::Relaxed);
+                first = false;
+            }
+            result
+        }).await;
+
+        let late = Instant::now().saturating_duration_since(deadline).as_micros() as u32;
+        WAKEUPS.fetch_add(1, Ordering::Relaxed);
+        LATENESS_SUM.fetch_add(late, Ordering::Relaxed);
+        LATENESS_MAX.fetch_max(late, Ordering::Relaxed);
+    }
+}
+
+#[embassy_executor::task]
+async fn report() {
+    loop {
+        Timer::after(REPORT_PERIOD).await;
+        let wakeups = WAKEUPS.swap(0, Ordering::Relaxed) as u64;
+        let polls = POLLS.swap(0, Ordering::Relaxed) as u64;
+        let sum = LATENESS_SUM.swap(0, Ordering::Relaxed) as u64;
+        let max = LATENESS_MAX.swap(0, Ordering::Relaxed);
+        let cycles = SCHEDULE_CYCLES.swap(0, Ordering::Relaxed) as u64;
+        print_fmt_func(format_args!(
+            "Timer queue bench: {} tasks  {} wakeups/s  lateness avg {} us  max {} us  schedule {} cycles/timer\n",
+            TASKS,
+            wakeups / REPORT_PERIOD.as_secs(),
+            if wakeups > 0 { sum / wakeups } else { 0 },
+            max,
+            if polls > 0 { cycles / polls } else { 0 },
+        ));
+    }
+}
+
+pub fn start() {
+    let spawner = executor::start(Priority::level::<5>());
+    for n in 0..TASKS {
+        spawner.must_spawn(sleeper(n));
+    }
+    // level 1 is taken by the nesting stress report, 7 is free in all examples
+    executor::start(Priority::level::<7>()).must_spawn(report());
+}
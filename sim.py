@@ -23,6 +23,8 @@ from litex.soc.integration.soc_core import *
 from litex.soc.integration.builder  import *
 from litex.soc.integration.soc      import *
 
+from litex.soc.interconnect import wishbone
+
 from litex.soc.cores.bitbang import *
 from litex.soc.cores.gpio    import GPIOTristate
 from litex.soc.cores.cpu     import CPUS
@@ -237,6 +239,31 @@ class VexRiscvSoftwareInterrupt(LiteXModule):
 
 
 
+# Machine Software Interrupt -----------------------------------------------------------------------
+
+class MachineSoftwareInterrupt(LiteXModule):
+    # Only the msip word of hart 0 of a CLINT, for the soft-irq-msip feature.
+    def __init__(self):
+        self.bus       = wishbone.Interface(data_width=32)
+        self.interrupt = Signal()
+
+        # # #
+
+        msip = Signal()
+        self.sync += [
+            self.bus.ack.eq(0),
+            If(self.bus.cyc & self.bus.stb & ~self.bus.ack,
+                self.bus.ack.eq(1),
+                If(self.bus.we & (self.bus.adr == 0) & self.bus.sel[0],
+                    msip.eq(self.bus.dat_w[0])
+                )
+            )
+        ]
+        self.comb += [
+            self.bus.dat_r.eq(msip),
+            self.interrupt.eq(msip),
+        ]
+
 # Platform -----------------------------------------------------------------------------------------
 
 
@@ -272,6 +299,7 @@ class SimSoC(SoCCore):
         sim_debug              = False,
         trace_reset_on         = False,
         with_jtag              = False,
+        with_clint_msip        = False,
         **kwargs):
 
         # Platform ---------------------------------------------------------------------------------
@@ -454,8 +482,14 @@ class SimSoC(SoCCore):
         self.cpu.timer = VexRiscvTimer()
         self.cpu.cpu_params.update(i_timerInterrupt=self.cpu.timer.interrupt)
 
-        self.cpu.software_interrupt = VexRiscvSoftwareInterrupt()
-        self.cpu.cpu_params.update(i_softwareInterrupt=self.cpu.software_interrupt.interrupt)
+        if with_clint_msip:
+            # standard msip, the levels are emulated in RAM by the soft-irq-msip feature
+            self.cpu.msip = MachineSoftwareInterrupt()
+            self.bus.add_slave("clint", self.cpu.msip.bus, SoCRegion(origin=0xf001_0000, size=0x1_0000, cached=False))
+            self.cpu.cpu_params.update(i_softwareInterrupt=self.cpu.msip.interrupt)
+        else:
+            self.cpu.software_interrupt = VexRiscvSoftwareInterrupt()
+            self.cpu.cpu_params.update(i_softwareInterrupt=self.cpu.software_interrupt.interrupt)
 
 
     def generate_rust_headers(self):
@@ -485,6 +519,16 @@ class SimSoC(SoCCore):
         self.logger.info("Generating Rust Regions:")
         includeFile = "// Auto generated header file for rust implementation.\n\n"
         
+        includeFile += "// SoC configuration\n"
+        for name, constant in self.constants.items():
+            value = getattr(constant, "value", constant)
+            # only integers, the crate has no use for strings and flags
+            if name.startswith("CONFIG_") and type(value) is int and 0 <= value <= 0xFFFFFFFF:
+                includeFile += "pub const " + name.ljust(36) + f":u32 = {value};\n"
+        if "CONFIG_CLOCK_FREQUENCY" not in self.constants:
+            includeFile += "pub const " + "CONFIG_CLOCK_FREQUENCY".ljust(36) + f":u32 = {int(self.sys_clk_freq)};\n"
+        includeFile += "\n"
+
         includeFile += "// IRQ definitions\n"
         count = 0
         for name, loc in self.irq.locs.items():
@@ -605,6 +649,9 @@ def sim_args(parser):
     # JTAG
     parser.add_argument("--with-jtagremote",      action="store_true", help="Enable jtagremote support")
 
+    # Software interrupt.
+    parser.add_argument("--with-clint-msip",      action="store_true",     help="Use a standard CLINT msip instead of the per-level software interrupt CSRs.")
+
     # GPIO.
     parser.add_argument("--with-gpio",            action="store_true",     help="Enable Tristate GPIO (32 pins).")
 
@@ -637,6 +684,9 @@ def main():
 
     # Configuration --------------------------------------------------------------------------------
 
+    # Timer0 uptime, read by the time-driver-timer0 feature of embassy-litex.
+    soc_kwargs["timer_uptime"] = True
+
     # UART.
     if soc_kwargs["uart_name"] == "serial":
         soc_kwargs["uart_name"] = "sim"
@@ -714,6 +764,7 @@ def main():
         with_analyzer          = args.with_analyzer,
         with_i2c               = args.with_i2c,
         with_jtag              = args.with_jtagremote,
+        with_clint_msip        = args.with_clint_msip,
         with_sdcard            = args.with_sdcard,
         with_spi_flash         = args.with_spi_flash,
         with_gpio              = args.with_gpio,
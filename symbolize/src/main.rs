@@ -0,0 +1,279 @@
+//! Symbolize the crash reports in a captured UART log.
+//!
+//! ```shell
+//! $ symbolize <elf> [<log>]
+//! ```
+//!
+//! Reads the log from the file or stdin and prints it unchanged, except that
+//! the addresses in the panic, exception and crash record blocks printed by
+//! `embassy-litex` are followed by function names, files and lines from the
+//! DWARF info of the firmware ELF. Inlined functions are listed innermost
+//! first.
+//!
+//! The firmware has to be built with debug info which is not stripped, and
+//! with frame pointers for a backtrace of more than one frame.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::ExitCode;
+
+use addr2line::Loader;
+
+const PANIC_HEADER: &str = "-------------- Panic";
+const EXCEPTION_HEADER: &str = "-------------- Exception";
+const RECORD_HEADER: &str = "-------------- Crash record";
+const END_MARKERS: [&str; 4] = ["Stopping CPU.", "Resetting CPU.", "Waiting for debugger.", "End of crash record."];
+
+/// Kind of an address found in a crash block.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Address {
+    /// Address of the faulting instruction.
+    Pc,
+    /// Return address, the call is the instruction before it.
+    Return,
+}
+
+/// Parse the hex number at the start of `s`, with or without `0x`.
+fn parse_hex(s: &str) -> Option<u64> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    let end = s.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(s.len());
+    if end == 0 {
+        return None;
+    }
+    u64::from_str_radix(&s[..end], 16).ok()
+}
+
+/// Addresses of a line of a crash block.
+fn addresses(line: &str) -> Vec<(Address, u64)> {
+    let mut found = Vec::new();
+
+    // backtrace frame `#n pc=0x...`
+    if line.starts_with('#') {
+        if let Some(pos) = line.find(" pc=") {
+            found.extend(parse_hex(&line[pos + 4..]).map(|a| (Address::Return, a)));
+        }
+        return found;
+    }
+    // exception header and panic message `... at pc 0x...`
+    if let Some(pos) = line.find(" at pc ") {
+        found.extend(parse_hex(&line[pos + 7..]).map(|a| (Address::Pc, a)));
+    }
+    // trap frame registers
+    if let Some(rest) = line.strip_prefix("PC=") {
+        found.extend(parse_hex(rest).map(|a| (Address::Pc, a)));
+    }
+    if let Some(pos) = line.find("RA/x1=") {
+        found.extend(parse_hex(&line[pos + 6..]).map(|a| (Address::Return, a)));
+    }
+    found
+}
+
+/// Write the functions and source lines at `addr`.
+fn symbolize(loader: &Loader, out: &mut impl Write, kind: Address, addr: u64) -> io::Result<()> {
+    let probe = match kind {
+        Address::Pc => addr,
+        Address::Return => addr.saturating_sub(1),
+    };
+
+    let mut printed = false;
+    if let Ok(mut frames) = loader.find_frames(probe) {
+        while let Ok(Some(frame)) = frames.next() {
+            let function = frame.function.as_ref()
+                .and_then(|f| f.demangle().ok())
+                .unwrap_or(Cow::Borrowed("??"));
+            let location = match frame.location {
+                Some(ref l) => format!("{}:{}", l.file.unwrap_or("??"), l.line.unwrap_or(0)),
+                None => "??:0".to_string(),
+            };
+            writeln!(out, "      {:#010x}: {}", addr, function)?;
+            writeln!(out, "                  at {}", location)?;
+            printed = true;
+        }
+    }
+
+    // no DWARF for the address, e.g. assembly or stripped debug info
+    if !printed {
+        match loader.find_symbol(probe) {
+            Some(name) => writeln!(out, "      {:#010x}: {}", addr, addr2line::demangle_auto(Cow::Borrowed(name), None))?,
+            None => writeln!(out, "      {:#010x}: ??", addr)?,
+        }
+    }
+    Ok(())
+}
+
+/// Copy `input` to `out`, `resolve` writes the symbols of every address found
+/// in a crash block.
+fn run<W: Write>(
+    input: impl BufRead,
+    out: &mut W,
+    mut resolve: impl FnMut(&mut W, Address, u64) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut in_crash = false;
+
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        writeln!(out, "{}", line)?;
+
+        if [PANIC_HEADER, EXCEPTION_HEADER, RECORD_HEADER].iter().any(|h| line.starts_with(h)) {
+            in_crash = true;
+            continue;
+        }
+        if !in_crash {
+            continue;
+        }
+        if END_MARKERS.iter().any(|m| line.starts_with(m)) {
+            in_crash = false;
+            continue;
+        }
+        for (kind, addr) in addresses(line) {
+            resolve(out, kind, addr)?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: {} <elf> [<log>]", args[0]);
+        return ExitCode::from(2);
+    }
+
+    let loader = match Loader::new(&args[1]) {
+        Ok(loader) => loader,
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let input: Box<dyn BufRead> = match args.get(2).map(String::as_str) {
+        None | Some("-") => Box::new(BufReader::new(io::stdin())),
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if let Err(e) = run(input, &mut out, |out, kind, addr| symbolize(&loader, out, kind, addr)) {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Output of `run` with every address replaced by its kind and value.
+    fn run_str(input: &str) -> String {
+        let mut out = Vec::new();
+        run(input.as_bytes(), &mut out, |out, kind, addr| writeln!(out, "  {:?} {:#x}", kind, addr)).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parse_hex_prefix() {
+        assert_eq!(parse_hex("0x40001234:"), Some(0x4000_1234));
+        assert_eq!(parse_hex("deadbeef, x"), Some(0xdead_beef));
+        assert_eq!(parse_hex("0x"), None);
+        assert_eq!(parse_hex("zz"), None);
+    }
+
+    #[test]
+    fn backtrace_frame() {
+        assert_eq!(addresses("#0 pc=0x40001234"), vec![(Address::Return, 0x4000_1234)]);
+        assert_eq!(addresses("#12 pc=0x00000010"), vec![(Address::Return, 0x10)]);
+        // a frame line is never read as a register line
+        assert_eq!(addresses("#1 pc=0x40000010 RA/x1=0x40000020"), vec![(Address::Return, 0x4000_0010)]);
+    }
+
+    #[test]
+    fn at_pc() {
+        assert_eq!(addresses("Illegal instruction at pc 0x40000100"), vec![(Address::Pc, 0x4000_0100)]);
+        assert_eq!(addresses("panicked at src/main.rs:10:5 at pc 0x40000200:"), vec![(Address::Pc, 0x4000_0200)]);
+    }
+
+    #[test]
+    fn trap_frame_registers() {
+        assert_eq!(addresses("PC=0x40000300     SP=0x40010000"), vec![(Address::Pc, 0x4000_0300)]);
+        assert_eq!(
+            addresses("RA/x1=0x40000400     T0/x5=0x00000001"),
+            vec![(Address::Return, 0x4000_0400)],
+        );
+        assert_eq!(
+            addresses("PC=0x40000300     RA/x1=0x40000400"),
+            vec![(Address::Pc, 0x4000_0300), (Address::Return, 0x4000_0400)],
+        );
+        // only at the start of a line
+        assert!(addresses("MEPC=0x40000300").is_empty());
+    }
+
+    #[test]
+    fn outside_crash_block() {
+        let input = "#0 pc=0x40000000\nPC=0x40000004\n";
+        assert_eq!(run_str(input), input);
+    }
+
+    #[test]
+    fn panic_block() {
+        let input = "-------------- Panic ------------\n\
+                     panicked at src/main.rs:1:1\n\
+                     Backtrace:\n\
+                     #0 pc=0x40000010\n\
+                     Stopping CPU.\n\
+                     #1 pc=0x40000020\n";
+        assert_eq!(run_str(input), "-------------- Panic ------------\n\
+                                    panicked at src/main.rs:1:1\n\
+                                    Backtrace:\n\
+                                    #0 pc=0x40000010\n  \
+                                    Return 0x40000010\n\
+                                    Stopping CPU.\n\
+                                    #1 pc=0x40000020\n");
+    }
+
+    #[test]
+    fn exception_block_crlf() {
+        let input = "-------------- Exception ------------\r\n\
+                     Load access fault at pc 0x40000100\r\n\
+                     PC=0x40000100     RA/x1=0x40000200\r\n\
+                     Resetting CPU.\r\n";
+        assert_eq!(run_str(input), "-------------- Exception ------------\n\
+                                    Load access fault at pc 0x40000100\n  \
+                                    Pc 0x40000100\n\
+                                    PC=0x40000100     RA/x1=0x40000200\n  \
+                                    Pc 0x40000100\n  \
+                                    Return 0x40000200\n\
+                                    Resetting CPU.\n");
+    }
+
+    #[test]
+    fn crash_record_block() {
+        let input = "-------------- Crash record ------------\r\n\
+                     #0 pc=0x40000010\r\n\
+                     End of crash record.\r\n\
+                     -------------- Panic ------------\r\n\
+                     #0 pc=0x40000030\r\n\
+                     Waiting for debugger.\r\n\
+                     #0 pc=0x40000040\r\n";
+        assert_eq!(run_str(input), "-------------- Crash record ------------\n\
+                                    #0 pc=0x40000010\n  \
+                                    Return 0x40000010\n\
+                                    End of crash record.\n\
+                                    -------------- Panic ------------\n\
+                                    #0 pc=0x40000030\n  \
+                                    Return 0x40000030\n\
+                                    Waiting for debugger.\n\
+                                    #0 pc=0x40000040\n");
+    }
+}